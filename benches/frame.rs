@@ -0,0 +1,78 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use p2p_video_chat::display::{DisplayOptions, TerminalDisplay};
+use p2p_video_chat::frame::{frames_differ, reduce_frame_size};
+
+const WIDTH: u32 = 640;
+const HEIGHT: u32 = 480;
+
+// Smooth gradients with a moving bright square, roughly what a webcam frame looks like to the renderer
+fn synthetic_frame(offset: u32) -> Vec<u8> {
+    let mut frame = Vec::with_capacity((WIDTH * HEIGHT * 3) as usize);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let in_square = (x + offset) % WIDTH < 120 && y > 180 && y < 300;
+            if in_square {
+                frame.extend_from_slice(&[250, 250, 240]);
+            } else {
+                frame.extend_from_slice(&[(x * 255 / WIDTH) as u8, (y * 255 / HEIGHT) as u8, ((x + y) % 256) as u8]);
+            }
+        }
+    }
+    frame
+}
+
+fn bench_frames_differ(c: &mut Criterion) {
+    let a = synthetic_frame(0);
+    let b = synthetic_frame(40);
+    c.bench_function("frames_differ/identical", |bench| {
+        bench.iter(|| frames_differ(black_box(&a), black_box(&a), 1))
+    });
+    c.bench_function("frames_differ/moved", |bench| {
+        bench.iter(|| frames_differ(black_box(&a), black_box(&b), 1))
+    });
+}
+
+fn bench_reduce_frame_size(c: &mut Criterion) {
+    let frame = synthetic_frame(0);
+    c.bench_function("reduce_frame_size/640x480_to_320x240", |bench| {
+        bench.iter(|| reduce_frame_size(black_box(&frame), WIDTH, HEIGHT, 320, 240))
+    });
+}
+
+fn bench_compose_frame(c: &mut Criterion) {
+    let frames = [synthetic_frame(0), synthetic_frame(40)];
+    let mut display = TerminalDisplay::new(WIDTH, HEIGHT, &DisplayOptions::default());
+    let mut i = 0;
+    c.bench_function("compose_frame/640x480", |bench| {
+        bench.iter(|| {
+            i = (i + 1) % frames.len();
+            black_box(display.compose_frame(black_box(&frames[i])).len())
+        })
+    });
+}
+
+// Fewer color bits should mostly show up as fewer bytes written, the time is about the same
+fn bench_compose_frame_color_bits(c: &mut Criterion) {
+    let frames = [synthetic_frame(0), synthetic_frame(40)];
+    let options = DisplayOptions {
+        color_bits: 4,
+        ..DisplayOptions::default()
+    };
+    let mut display = TerminalDisplay::new(WIDTH, HEIGHT, &options);
+    let mut i = 0;
+    c.bench_function("compose_frame/640x480/4-bit", |bench| {
+        bench.iter(|| {
+            i = (i + 1) % frames.len();
+            black_box(display.compose_frame(black_box(&frames[i])).len())
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_frames_differ,
+    bench_reduce_frame_size,
+    bench_compose_frame,
+    bench_compose_frame_color_bits
+);
+criterion_main!(benches);
@@ -1,33 +1,187 @@
 use nokhwa::{
     pixel_format::RgbFormat,
-    utils::{CameraIndex, RequestedFormat, RequestedFormatType, Resolution, FrameFormat, CameraFormat},
-    Camera
+    utils::{ApiBackend, CameraIndex, RequestedFormat, RequestedFormatType, Resolution, FrameFormat, CameraFormat},
+    Camera,
+    NokhwaError,
 };
 use anyhow::Result;
-use std::sync::atomic::{AtomicBool, Ordering};
+use p2p_video_chat::codec::Frame;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, TrySendError};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[cfg(windows)]
-use windows::Win32::System::Com::{CoInitializeEx, CoUninitialize, COINIT_MULTITHREADED};
+use crate::config::{CachedFormat, CameraCache};
+
+#[derive(Debug)]
+pub enum CameraError {
+    HardwareTransient(String),
+    DeviceBusy(String),
+    NotFound(String),
+    Decode(String),
+    Other(String),
+}
+
+impl fmt::Display for CameraError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CameraError::HardwareTransient(msg) => write!(f, "transient camera hardware error: {}", msg),
+            CameraError::DeviceBusy(msg) => write!(f, "camera is busy: {}", msg),
+            CameraError::NotFound(msg) => write!(f, "camera not found: {}", msg),
+            CameraError::Decode(msg) => write!(f, "failed to decode camera frame: {}", msg),
+            CameraError::Other(msg) => write!(f, "camera error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CameraError {}
+
+impl From<NokhwaError> for CameraError {
+    fn from(e: NokhwaError) -> Self {
+        let msg = e.to_string();
+        match e {
+            NokhwaError::ProcessFrameError { .. } => CameraError::Decode(msg),
+            NokhwaError::OpenDeviceError(..) => CameraError::NotFound(msg),
+            NokhwaError::OpenStreamError(..) => CameraError::DeviceBusy(msg),
+            // Media Foundation reports MFT/hardware hiccups (e.g. 0xC00D3704) as plain read failures.
+            // A busy device already fails when the stream opens, so a failed read is taken as transient
+            NokhwaError::ReadFrameError(..) => CameraError::HardwareTransient(msg),
+            _ => CameraError::Other(msg),
+        }
+    }
+}
+
+pub mod platform {
+    #[cfg(windows)]
+    use windows::Win32::System::Com::{CoInitializeEx, CoUninitialize, COINIT_APARTMENTTHREADED, COINIT_MULTITHREADED};
+
+    // Keeps COM initialized on the current thread for as long as it lives; a no-op off Windows
+    pub struct ComGuard {
+        #[cfg(windows)]
+        initialized: bool,
+    }
+
+    impl ComGuard {
+        pub fn init() -> Self {
+            #[cfg(windows)]
+            {
+                let initialized = unsafe {
+                    if CoInitializeEx(None, COINIT_APARTMENTTHREADED).is_ok() {
+                        true
+                    } else {
+                        // The thread already uses another threading model, join it as multithreaded
+                        let hr = CoInitializeEx(None, COINIT_MULTITHREADED);
+                        if hr.is_err() {
+                            eprintln!("Warning: Could not initialize COM at all: {:?}", hr);
+                        }
+                        hr.is_ok()
+                    }
+                };
+                Self { initialized }
+            }
+            #[cfg(not(windows))]
+            {
+                Self {}
+            }
+        }
+    }
+
+    impl Drop for ComGuard {
+        fn drop(&mut self) {
+            #[cfg(windows)]
+            {
+                if self.initialized {
+                    unsafe { CoUninitialize() };
+                }
+            }
+        }
+    }
+}
+
+// How many ticks to sit out after a capture fails while the camera is already unhealthy
+const CAPTURE_COOLDOWN_TICKS: u32 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureState {
+    Healthy,
+    // Capture on every other tick to give a struggling camera some slack
+    Degraded,
+    Cooldown { remaining: u32 },
+}
+
+// Decides per interval tick whether the loop should try to grab a frame
+#[derive(Debug)]
+pub struct CaptureGate {
+    state: CaptureState,
+    degraded_ticks: u32,
+}
+
+impl CaptureGate {
+    pub fn new() -> Self {
+        Self {
+            state: CaptureState::Healthy,
+            degraded_ticks: 0,
+        }
+    }
+
+    pub fn should_capture(&mut self, camera_healthy: bool) -> bool {
+        if let CaptureState::Cooldown { remaining } = &mut self.state {
+            if *remaining > 0 {
+                *remaining -= 1;
+                return false;
+            }
+        }
+
+        if camera_healthy {
+            self.state = CaptureState::Healthy;
+            self.degraded_ticks = 0;
+            return true;
+        }
+
+        if self.state != CaptureState::Degraded {
+            self.state = CaptureState::Degraded;
+            self.degraded_ticks = 0;
+        }
+        self.degraded_ticks = self.degraded_ticks.wrapping_add(1);
+        self.degraded_ticks.is_multiple_of(2)
+    }
+
+    pub fn capture_failed(&mut self) {
+        if self.state == CaptureState::Degraded {
+            self.state = CaptureState::Cooldown {
+                remaining: CAPTURE_COOLDOWN_TICKS,
+            };
+        }
+    }
+}
+
+impl Default for CaptureGate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 pub struct CameraCapture {
     camera: Camera,
     buffer: Vec<u8>,
     backup_buffer: Vec<u8>,
+    // Dimensions of what is actually in `buffer` / `backup_buffer`, as decoded
+    frame_size: (u32, u32),
+    backup_size: (u32, u32),
     consecutive_failures: u32,
     is_healthy: Arc<AtomicBool>,
     frame_pool: Vec<Vec<u8>>,
     current_pool_index: usize,
+    // Declared last so COM is torn down only after the camera itself
+    _com: platform::ComGuard,
 }
 
 impl CameraCapture {
     pub fn new() -> Result<Self> {
-        #[cfg(windows)]
-        unsafe {
-            let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
-        }
+        let com = platform::ComGuard::init();
 
-        let formats = vec![
+        let formats = [
             RequestedFormat::new::<RgbFormat>(RequestedFormatType::Exact(CameraFormat::new(
                 Resolution::new(640, 480),
                 FrameFormat::MJPEG,
@@ -54,12 +208,36 @@ impl CameraCapture {
                 30
             ))),
         ];
+
+        // Whatever worked last time for a camera that is still plugged in goes first
+        let mut cache = CameraCache::load();
+        let present: Vec<String> = nokhwa::query(ApiBackend::Auto)
+            .map(|devices| devices.iter().map(|device| device.human_name()).collect())
+            .unwrap_or_default();
+        let cached: Vec<CachedFormat> = present
+            .iter()
+            .filter_map(|device| cache.devices.get(device).copied())
+            .filter(|choice| choice.format < formats.len())
+            .collect();
+        for choice in cached {
+            match Self::try_open_camera(choice.camera_index, formats[choice.format]) {
+                Ok(camera) => return Ok(Self::with_camera(camera, com)),
+                Err(e) => eprintln!(
+                    "Camera {} with cached format {} failed, probing all formats: {}",
+                    choice.camera_index, choice.format, e
+                ),
+            }
+        }
         
         for (format_idx, format) in formats.iter().enumerate() {
             for camera_index in [0, 1, 2] {
-                match Self::try_create_camera(camera_index, format.clone()) {
-                    Ok(camera_capture) => {
-                        return Ok(camera_capture);
+                match Self::try_open_camera(camera_index, *format) {
+                    Ok(camera) => {
+                        let choice = CachedFormat { camera_index, format: format_idx };
+                        if let Err(e) = cache.remember(camera.info().human_name(), choice) {
+                            eprintln!("Could not cache the camera format: {}", e);
+                        }
+                        return Ok(Self::with_camera(camera, com));
                     }
                     Err(e) => {
                         eprintln!("Camera {} with format {} failed: {}", camera_index, format_idx, e);
@@ -72,7 +250,7 @@ impl CameraCapture {
         Err(anyhow::anyhow!("Failed to initialize camera with any high-performance format. Windows troubleshooting:\n1. Close all camera applications (Skype, Teams, OBS, etc.)\n2. Run as administrator\n3. Check Windows Privacy Settings > Camera\n4. Restart Windows if issues persist"))
     }
     
-    fn try_create_camera(camera_index: u32, format: RequestedFormat) -> Result<Self> {
+    fn try_open_camera(camera_index: u32, format: RequestedFormat) -> Result<Camera> {
         std::thread::sleep(std::time::Duration::from_millis(25));
         
         let mut camera = Camera::new(CameraIndex::Index(camera_index), format)?;
@@ -95,6 +273,10 @@ impl CameraCapture {
         
         std::thread::sleep(std::time::Duration::from_millis(200));
         
+        Ok(camera)
+    }
+    
+    fn with_camera(camera: Camera, com: platform::ComGuard) -> Self {
         let res = camera.resolution();
         let buffer_size = (res.width() * res.height() * 3) as usize;
         
@@ -103,23 +285,27 @@ impl CameraCapture {
             frame_pool.push(vec![0u8; buffer_size]);
         }
         
-        Ok(Self { 
+        Self { 
             camera,
             buffer: Vec::with_capacity(buffer_size),
             backup_buffer: vec![0u8; buffer_size],
+            frame_size: (res.width(), res.height()),
+            backup_size: (res.width(), res.height()),
             consecutive_failures: 0,
             is_healthy: Arc::new(AtomicBool::new(true)),
             frame_pool,
             current_pool_index: 0,
-        })
+            _com: com,
+        }
     }
     
-    pub fn get_frame(&mut self) -> Result<&[u8]> {
+    // Returns the frame with the dimensions it was decoded at, which can differ from the negotiated format
+    pub fn get_frame(&mut self) -> Result<(&[u8], u32, u32)> {
         match self.try_get_frame_fast() {
             Ok(_) => {
                 self.consecutive_failures = 0;
                 self.is_healthy.store(true, Ordering::Relaxed);
-                return Ok(&self.buffer);
+                Ok(self.current_frame())
             }
             Err(e) => {
                 self.consecutive_failures += 1;
@@ -128,33 +314,41 @@ impl CameraCapture {
                     self.is_healthy.store(false, Ordering::Relaxed);
                 }
                 
-                let error_msg = e.to_string();
-                if error_msg.contains("0xC00D3704") || 
-                   error_msg.contains("MFT") ||
-                   error_msg.contains("hardware") ||
-                   self.consecutive_failures > 3 {
-                    
-                    self.buffer.clear();
-                    self.buffer.extend_from_slice(&self.backup_buffer);
-                    return Ok(&self.buffer);
+                // Retrying immediately only helps for decode hiccups, not device-level failures
+                let retry_pointless = matches!(
+                    e,
+                    CameraError::HardwareTransient(_) | CameraError::DeviceBusy(_) | CameraError::NotFound(_)
+                );
+                if retry_pointless || self.consecutive_failures > 3 {
+                    self.restore_backup();
+                    return Ok(self.current_frame());
                 }
                 
                 match self.try_get_frame_fast() {
                     Ok(_) => {
                         self.consecutive_failures = 0;
-                        return Ok(&self.buffer);
+                        Ok(self.current_frame())
                     }
                     Err(_) => {
-                        self.buffer.clear();
-                        self.buffer.extend_from_slice(&self.backup_buffer);
-                        return Ok(&self.buffer);
+                        self.restore_backup();
+                        Ok(self.current_frame())
                     }
                 }
             }
         }
     }
+
+    fn current_frame(&self) -> (&[u8], u32, u32) {
+        (&self.buffer, self.frame_size.0, self.frame_size.1)
+    }
+
+    fn restore_backup(&mut self) {
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&self.backup_buffer);
+        self.frame_size = self.backup_size;
+    }
     
-    fn try_get_frame_fast(&mut self) -> Result<()> {
+    fn try_get_frame_fast(&mut self) -> Result<(), CameraError> {
         let frame = self.camera.frame()?;
         let img = frame.decode_image::<RgbFormat>()?;
         let raw_data = img.as_raw();
@@ -166,17 +360,15 @@ impl CameraCapture {
             
             std::mem::swap(&mut self.buffer, target_buffer);
             self.buffer.truncate(raw_data.len());
-            
-            if self.buffer.len() <= self.backup_buffer.len() {
-                self.backup_buffer[..self.buffer.len()].copy_from_slice(&self.buffer);
-            }
         } else {
             self.buffer.clear();
             self.buffer.extend_from_slice(raw_data);
-            
-            self.backup_buffer.clear();
-            self.backup_buffer.extend_from_slice(&self.buffer);
         }
+        self.frame_size = (img.width(), img.height());
+
+        self.backup_buffer.clear();
+        self.backup_buffer.extend_from_slice(&self.buffer);
+        self.backup_size = self.frame_size;
         
         self.current_pool_index = (self.current_pool_index + 1) % self.frame_pool.len();
         
@@ -187,20 +379,274 @@ impl CameraCapture {
         self.is_healthy.load(Ordering::Relaxed)
     }
     
+    pub fn format_description(&self) -> String {
+        self.camera.camera_format().to_string()
+    }
+
     pub fn dimensions(&self) -> (u32, u32) {
         let res = self.camera.resolution();
         (res.width(), res.height())
     }
+
+    pub fn frame_rate(&self) -> u32 {
+        self.camera.frame_rate()
+    }
 }
 
 impl Drop for CameraCapture {
     fn drop(&mut self) {
         let _ = self.camera.stop_stream();
         std::thread::sleep(std::time::Duration::from_millis(50));
-        
-        #[cfg(windows)]
-        unsafe {
-            CoUninitialize();
+    }
+}
+
+// What a source negotiated when it was opened; `fps` is 0 when the source doesn't say
+#[derive(Debug, Clone, Copy)]
+pub struct SourceInfo {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+
+// Anything the capture thread can pull RGB frames from
+pub trait FrameSource {
+    fn info(&self) -> SourceInfo;
+    fn grab(&mut self) -> Result<Frame>;
+    fn is_healthy(&self) -> bool;
+}
+
+impl FrameSource for CameraCapture {
+    fn info(&self) -> SourceInfo {
+        let (width, height) = self.dimensions();
+        SourceInfo { width, height, fps: self.frame_rate() }
+    }
+
+    fn grab(&mut self) -> Result<Frame> {
+        let (data, width, height) = self.get_frame()?;
+        Ok(Frame { data: data.to_vec(), width, height })
+    }
+
+    fn is_healthy(&self) -> bool {
+        CameraCapture::is_healthy(self)
+    }
+}
+
+// Opens the source on the capture thread itself, which matters for COM on Windows; kept
+// around so the watchdog can open it again
+pub type OpenSource = Arc<dyn Fn() -> Result<Box<dyn FrameSource>> + Send + Sync>;
+
+// A grab that takes longer than this is treated as a wedged source and the source is reopened
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Runs a `FrameSource` on its own OS thread so a hung nokhwa call can't stall the async loop.
+// A wedged thread can't be killed, so a restart abandons it: it exits once its grab returns
+// and it sees that its generation is no longer current.
+pub struct CaptureWorker {
+    open: OpenSource,
+    name: &'static str,
+    frames: Receiver<Result<Frame, String>>,
+    generation: Arc<AtomicU64>,
+    is_healthy: Arc<AtomicBool>,
+    last_frame_at: Instant,
+}
+
+impl CaptureWorker {
+    // Blocks until the source is open, so startup failures are reported like before
+    pub fn start(open: OpenSource, name: &'static str) -> Result<(Self, SourceInfo)> {
+        let generation = Arc::new(AtomicU64::new(0));
+        let is_healthy = Arc::new(AtomicBool::new(true));
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let frames = Self::spawn(&open, name, 0, &generation, &is_healthy, Some(ready_tx));
+        let info = ready_rx
+            .recv()
+            .map_err(|_| anyhow::anyhow!("{} thread exited during startup", name))??;
+        let worker = Self {
+            open,
+            name,
+            frames,
+            generation,
+            is_healthy,
+            last_frame_at: Instant::now(),
+        };
+        Ok((worker, info))
+    }
+
+    fn spawn(
+        open: &OpenSource,
+        name: &'static str,
+        generation: u64,
+        current: &Arc<AtomicU64>,
+        is_healthy: &Arc<AtomicBool>,
+        ready: Option<mpsc::Sender<Result<SourceInfo>>>,
+    ) -> Receiver<Result<Frame, String>> {
+        // Small on purpose: the loop only wants the newest frame, older ones are dropped here
+        let (tx, rx) = mpsc::sync_channel(2);
+        let open = open.clone();
+        let current = current.clone();
+        let is_healthy = is_healthy.clone();
+        std::thread::spawn(move || {
+            let mut source = match open() {
+                Ok(source) => source,
+                Err(e) => {
+                    match ready {
+                        Some(ready) => {
+                            let _ = ready.send(Err(e));
+                        }
+                        None => {
+                            let _ = tx.try_send(Err(format!("failed to reopen {}: {}", name, e)));
+                        }
+                    }
+                    return;
+                }
+            };
+            if let Some(ready) = ready {
+                let _ = ready.send(Ok(source.info()));
+            }
+            while current.load(Ordering::Acquire) == generation {
+                let result = source.grab().map_err(|e| e.to_string());
+                is_healthy.store(source.is_healthy(), Ordering::Relaxed);
+                if let Err(TrySendError::Disconnected(_)) = tx.try_send(result) {
+                    break;
+                }
+                // A failing source tends to return immediately, don't spin on it
+                if !source.is_healthy() {
+                    std::thread::sleep(Duration::from_millis(33));
+                }
+            }
+        });
+        rx
+    }
+
+    // Newest frame since the last call, if any. Also where the watchdog runs, so a stuck
+    // source turns into an error the caller handles like any other capture failure
+    pub fn poll(&mut self) -> Option<Result<Frame>> {
+        let mut latest = None;
+        // A disconnected channel means the thread gave up, which the watchdog below handles
+        while let Ok(result) = self.frames.try_recv() {
+            latest = Some(result);
         }
+        if let Some(result) = latest {
+            self.last_frame_at = Instant::now();
+            return Some(result.map_err(|e| anyhow::anyhow!(e)));
+        }
+        if self.last_frame_at.elapsed() < WATCHDOG_TIMEOUT {
+            return None;
+        }
+        self.restart();
+        Some(Err(anyhow::anyhow!(
+            "no frame from the {} for {}s, reopening it",
+            self.name,
+            WATCHDOG_TIMEOUT.as_secs()
+        )))
+    }
+
+    fn restart(&mut self) {
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        self.is_healthy.store(false, Ordering::Relaxed);
+        self.frames = Self::spawn(&self.open, self.name, generation, &self.generation, &self.is_healthy, None);
+        // Opening takes a while, give the new thread a full timeout before judging it
+        self.last_frame_at = Instant::now();
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.is_healthy.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for CaptureWorker {
+    fn drop(&mut self) {
+        // Stops the current thread after its next grab, which releases the device
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nokhwa_errors_map_by_variant() {
+        let decode = NokhwaError::ProcessFrameError {
+            src: FrameFormat::MJPEG,
+            destination: "RGB".to_string(),
+            error: "bad huffman table".to_string(),
+        };
+        assert!(matches!(CameraError::from(decode), CameraError::Decode(_)));
+        assert!(matches!(
+            CameraError::from(NokhwaError::OpenDeviceError("0".to_string(), "no such device".to_string())),
+            CameraError::NotFound(_)
+        ));
+        assert!(matches!(
+            CameraError::from(NokhwaError::OpenStreamError("device or resource busy".to_string())),
+            CameraError::DeviceBusy(_)
+        ));
+        assert!(matches!(
+            CameraError::from(NokhwaError::GeneralError("unknown".to_string())),
+            CameraError::Other(_)
+        ));
+    }
+
+    #[test]
+    fn read_failures_are_transient_whatever_the_message_says() {
+        for message in ["0xC00D3704", "device busy", "camera in use"] {
+            let error = CameraError::from(NokhwaError::ReadFrameError(message.to_string()));
+            assert!(matches!(&error, CameraError::HardwareTransient(msg) if msg.contains(message)));
+        }
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn com_guard_is_a_no_op_off_windows() {
+        assert_eq!(std::mem::size_of::<platform::ComGuard>(), 0);
+        let outer = platform::ComGuard::init();
+        let inner = platform::ComGuard::init();
+        drop(inner);
+        drop(outer);
+    }
+
+    #[test]
+    fn opening_without_a_camera_is_an_error() {
+        let present = nokhwa::query(ApiBackend::Auto).map(|devices| devices.len()).unwrap_or(0);
+        // Only meaningful on machines without a camera, like CI
+        if present > 0 {
+            return;
+        }
+        assert!(CameraCapture::new().is_err());
+    }
+
+    #[test]
+    fn healthy_camera_captures_every_tick() {
+        let mut gate = CaptureGate::new();
+        assert!((0..10).all(|_| gate.should_capture(true)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn unhealthy_camera_captures_every_other_tick() {
+        let mut gate = CaptureGate::new();
+        let ticks: Vec<bool> = (0..6).map(|_| gate.should_capture(false)).collect();
+        assert_eq!(ticks, [false, true, false, true, false, true]);
+    }
+
+    #[test]
+    fn failing_while_unhealthy_sits_out_the_cooldown() {
+        let mut gate = CaptureGate::new();
+        gate.should_capture(false);
+        gate.capture_failed();
+        for _ in 0..CAPTURE_COOLDOWN_TICKS {
+            assert!(!gate.should_capture(true));
+        }
+        assert!(gate.should_capture(true));
+    }
+
+    #[test]
+    fn recovery_resets_the_degraded_cadence() {
+        let mut gate = CaptureGate::new();
+        gate.should_capture(false);
+        assert!(gate.should_capture(false));
+        assert!(gate.should_capture(true));
+        assert_eq!(gate.state, CaptureState::Healthy);
+        // Starts the every-other-tick pattern over instead of carrying the old phase
+        assert!(!gate.should_capture(false));
+        assert!(gate.should_capture(false));
+    }
+}
@@ -1,314 +1,708 @@
 use std::{
-    collections::HashMap, 
-    fmt, 
-    str::FromStr, 
-    fs, 
-    sync::{Arc, Mutex},
-    io::{self, Write}
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{File, OpenOptions},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    io::{self, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result};
+use colored::{Color, Colorize};
 use futures_lite::StreamExt;
-use iroh::{Endpoint, NodeAddr, NodeId, Watcher};
-use iroh_gossip::{
-    api::{Event, GossipReceiver},
-    net::{Gossip, GOSSIP_ALPN},
-    proto::TopicId,
-};
-use serde::{Deserialize, Serialize};
+use iroh::{Endpoint, NodeId};
+use iroh_gossip::api::{Event, GossipReceiver, GossipSender};
+use rustyline::{error::ReadlineError, DefaultEditor, ExternalPrinter};
 use tokio::sync::mpsc;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-#[derive(Parser)]
-#[command(name = "p2p-chat", about = "peer-to-peer chat app using Iroh")]
-struct Cli {
-    #[command(subcommand)]
-    commands: Commands,
-}
+use p2p_video_chat::codec::Capabilities;
+use p2p_video_chat::display::term_size;
 
-#[derive(Subcommand)]
-enum Commands {
-    Open,
-    Join { ticket: String },
-}
+use p2p_video_chat::events::{AppEvent, EventSink};
+use p2p_video_chat::room::{
+    send_room_full, send_room_info, short_id, Activity, Failure, Message, MessageBody, Neighbors, Rejoin, RoomSettings,
+    MAX_MESSAGE_SIZE, ROOM_CAPACITY,
+};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Message {
-    body: MessageBody,
-    nonce: [u8; 16],
+use crate::{close_room, idle_timeout, spawn_until_shutdown};
+
+pub struct ChatOptions {
+    pub nick: Option<String>,
+    pub log_file: Option<PathBuf>,
+    pub scrollback: usize,
+    pub proof: Option<[u8; 32]>,
+    pub title: Option<String>,
+    pub is_host: bool,
+    pub rejoin: Rejoin,
+    pub peer_timeout: Option<Duration>,
+    pub idle_timeout_secs: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum MessageBody {
-    AboutMe { from: NodeId },
-    Message { from: NodeId, text: String },
+// Lines keep absolute indices so pending acks survive older lines being dropped
+struct Scrollback {
+    lines: VecDeque<String>,
+    dropped: usize,
+    cap: usize,
 }
 
-impl Message {
-    fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        serde_json::from_slice(bytes).map_err(Into::into)
+impl Scrollback {
+    fn new(cap: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            dropped: 0,
+            cap: cap.max(1),
+        }
     }
 
-    fn new(body: MessageBody) -> Self {
-        Self {
-            body,
-            nonce: rand::random(),
+    fn push(&mut self, line: String) -> usize {
+        if self.lines.len() == self.cap {
+            self.lines.pop_front();
+            self.dropped += 1;
         }
+        self.lines.push_back(line);
+        self.dropped + self.lines.len() - 1
     }
 
-    fn to_vec(&self) -> Vec<u8> {
-        serde_json::to_vec(self).expect("Serialization should never fail")
+    fn get_mut(&mut self, index: usize) -> Option<&mut String> {
+        index
+            .checked_sub(self.dropped)
+            .and_then(|i| self.lines.get_mut(i))
     }
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct CompactNodeInfo {
-    node_id: NodeId,
-    direct_addresses: Vec<std::net::SocketAddr>,
+    fn last(&self, n: usize) -> impl Iterator<Item = &String> {
+        self.lines.iter().skip(self.lines.len().saturating_sub(n))
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Ticket {
-    topic: TopicId,
-    nodes: Vec<CompactNodeInfo>,
+type Printer = Box<dyn ExternalPrinter + Send>;
+
+const PEER_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Magenta,
+    Color::Yellow,
+    Color::Green,
+    Color::Blue,
+    Color::Red,
+];
+
+struct RosterEntry {
+    nick: Option<String>,
+    color: Color,
+    last_seen: Instant,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TicketRegistry {
-    tickets: HashMap<String, Ticket>,
+// Everyone seen in the room, each with a color kept for the whole session
+#[derive(Default)]
+struct Roster {
+    peers: HashMap<NodeId, RosterEntry>,
+    joined: usize,
 }
 
-impl TicketRegistry {
-    fn load_or_create() -> Self {
-        let path = dirs::home_dir()
-            .unwrap_or_else(|| std::env::current_dir().unwrap())
-            .join(".p2p-video-chat-tickets.json");
-        
-        if let Ok(content) = fs::read_to_string(&path) {
-            if let Ok(registry) = serde_json::from_str(&content) {
-                return registry;
+impl Roster {
+    // Returns true for a peer that wasn't on the roster yet
+    fn seen(&mut self, peer: NodeId, nick: Option<String>) -> bool {
+        if let Some(entry) = self.peers.get_mut(&peer) {
+            entry.last_seen = Instant::now();
+            if nick.is_some() {
+                entry.nick = nick;
             }
+            return false;
         }
-        
-        Self { tickets: HashMap::new() }
+        let color = PEER_COLORS[self.joined % PEER_COLORS.len()];
+        self.joined += 1;
+        self.peers.insert(peer, RosterEntry { nick, color, last_seen: Instant::now() });
+        true
     }
-    
-    fn save(&self) -> Result<()> {
-        let path = dirs::home_dir()
-            .unwrap_or_else(|| std::env::current_dir().unwrap())
-            .join(".p2p-video-chat-tickets.json");
-        
-        fs::write(path, serde_json::to_string_pretty(self)?)?;
-        Ok(())
-    }
-    
-    fn generate_short_code(&self) -> String {
-        let chars = b"0123456789abcdefghijklmnopqrstuvwxyz";
-        loop {
-            let code: String = (0..8)
-                .map(|_| chars[rand::random::<usize>() % chars.len()] as char)
-                .collect();
-            
-            if !self.tickets.contains_key(&code) {
-                return code;
-            }
-        }
-    }
-    
-    fn register_ticket(&mut self, ticket: Ticket) -> Result<String> {
-        let code = self.generate_short_code();
-        self.tickets.insert(code.clone(), ticket);
-        self.save()?;
-        Ok(code)
-    }
-    
-    fn get_ticket(&self, code: &str) -> Option<&Ticket> {
-        self.tickets.get(code)
-    }
-}
 
-impl Ticket {
-    fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        postcard::from_bytes(bytes).map_err(Into::into)
+    // A peer that isn't on the roster yet has no place left with this many people in the room
+    fn is_full_without(&self, peer: &NodeId) -> bool {
+        !self.peers.contains_key(peer) && self.peers.len() + 1 >= ROOM_CAPACITY
     }
 
-    fn to_bytes(&self) -> Vec<u8> {
-        postcard::to_allocvec(self).expect("Serialization should never fail")
+    // Drops peers quiet for longer than `timeout`, returning their labels for the notice
+    fn prune(&mut self, timeout: Duration) -> Vec<String> {
+        let silent: Vec<NodeId> = self
+            .peers
+            .iter()
+            .filter(|(_, entry)| entry.last_seen.elapsed() > timeout)
+            .map(|(peer, _)| *peer)
+            .collect();
+        silent
+            .into_iter()
+            .map(|peer| {
+                let label = self.label(&peer);
+                self.peers.remove(&peer);
+                label
+            })
+            .collect()
     }
 
-    fn to_short_code(&self) -> Result<String> {
-        let mut registry = TicketRegistry::load_or_create();
-        registry.register_ticket(self.clone())
-    }
-    
-    fn from_code_or_full(input: &str) -> Result<Self> {
-        if input.len() <= 8 {
-            if let Some(ticket) = TicketRegistry::load_or_create().get_ticket(input) {
-                return Ok(ticket.clone());
+    fn label(&self, peer: &NodeId) -> String {
+        match self.peers.get(peer) {
+            Some(entry) => {
+                let name = entry.nick.clone().unwrap_or_else(|| short_id(peer));
+                name.color(entry.color).to_string()
             }
+            None => short_id(peer),
         }
-        input.parse()
-    }
-}
-
-impl fmt::Display for Ticket {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", data_encoding::BASE64URL_NOPAD.encode(&self.to_bytes()))
-    }
-}
-
-impl FromStr for Ticket {
-    type Err = anyhow::Error;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let bytes = data_encoding::BASE64URL_NOPAD.decode(s.as_bytes())?;
-        Self::from_bytes(&bytes)
     }
 }
 
 #[derive(Clone)]
 struct TerminalUI {
-    messages: Arc<Mutex<Vec<String>>>,
-    current_input: Arc<Mutex<String>>,
+    messages: Arc<Mutex<Scrollback>>,
+    // Set once the line editor runs; output then goes through it so the input line survives
+    printer: Arc<Mutex<Option<Printer>>>,
+    pending_acks: Arc<Mutex<HashMap<[u8; 16], usize>>>,
+    title: Arc<Mutex<Option<String>>>,
+    roster: Arc<Mutex<Roster>>,
+    log: Option<Arc<Mutex<File>>>,
+    // Set when the room ended under us; the input thread stops after the next line so the
+    // editor leaves raw mode before the process exits
+    closed: Arc<AtomicBool>,
 }
 
 impl TerminalUI {
-    fn new() -> Self {
+    fn new(scrollback: usize, log: Option<File>) -> Self {
         Self {
-            messages: Arc::new(Mutex::new(Vec::new())),
-            current_input: Arc::new(Mutex::new(String::new())),
+            messages: Arc::new(Mutex::new(Scrollback::new(scrollback))),
+            printer: Arc::new(Mutex::new(None)),
+            pending_acks: Arc::new(Mutex::new(HashMap::new())),
+            title: Arc::new(Mutex::new(None)),
+            roster: Arc::new(Mutex::new(Roster::default())),
+            log: log.map(|file| Arc::new(Mutex::new(file))),
+            closed: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    fn close(&self, reason: &str) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.add_message(format!("{}, press Enter to exit", reason));
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
     fn add_message(&self, msg: String) {
+        self.log_line(&msg);
         self.messages.lock().unwrap().push(msg);
         self.redraw();
     }
 
-    fn update_input(&self, input: String) {
-        *self.current_input.lock().unwrap() = input;
+    // The transcript gets plain text only, whatever decoration the screen shows
+    fn log_line(&self, msg: &str) {
+        if let Some(log) = &self.log {
+            let _ = writeln!(log.lock().unwrap(), "[{}] {}", utc_timestamp(), strip_ansi(msg));
+        }
+    }
+
+    fn add_outgoing(&self, msg: String, nonce: [u8; 16]) {
+        self.log_line(&msg);
+        let index = self.messages.lock().unwrap().push(msg);
+        self.pending_acks.lock().unwrap().insert(nonce, index);
+        self.redraw();
+    }
+
+    // The first ack from any peer marks the line as delivered
+    fn mark_delivered(&self, nonce: &[u8; 16]) {
+        let Some(index) = self.pending_acks.lock().unwrap().remove(nonce) else {
+            return;
+        };
+        if let Some(line) = self.messages.lock().unwrap().get_mut(index) {
+            line.push_str(" ✓");
+        }
+        self.redraw();
+    }
+
+    // Returns false when the title was already showing
+    fn set_title(&self, title: String) -> bool {
+        {
+            let mut current = self.title.lock().unwrap();
+            if current.as_ref() == Some(&title) {
+                return false;
+            }
+            *current = Some(title);
+        }
+        self.redraw();
+        true
+    }
+
+    fn label(&self, peer: &NodeId) -> String {
+        self.roster.lock().unwrap().label(peer)
+    }
+
+    fn show_roster(&self) {
+        let lines: Vec<String> = {
+            let roster = self.roster.lock().unwrap();
+            let mut peers: Vec<_> = roster.peers.iter().collect();
+            peers.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.last_seen));
+            peers
+                .into_iter()
+                .map(|(peer, entry)| {
+                    format!(
+                        "  {} ({}), last seen {}s ago",
+                        roster.label(peer),
+                        short_id(peer),
+                        entry.last_seen.elapsed().as_secs()
+                    )
+                })
+                .collect()
+        };
+        if lines.is_empty() {
+            self.add_message("nobody else is in the room".to_string());
+            return;
+        }
+        self.add_message(format!("in the room ({}):", lines.len()));
+        for line in lines {
+            self.add_message(line);
+        }
+    }
+
+    fn set_printer(&self, printer: Printer) {
+        *self.printer.lock().unwrap() = Some(printer);
         self.redraw();
     }
 
     fn redraw(&self) {
-        // FIX!! Clear the screen
-        print!("\x1B[2J\x1B[1;1H");
+        let mut screen = String::from("\x1B[2J\x1B[1;1H");
         
-        for msg in self.messages.lock().unwrap().iter() {
-            println!("{}", msg);
+        let (cols, rows) = term_size();
+        let rows = rows.saturating_sub(2);
+        let occupancy = format!(
+            "{}/{} in room",
+            self.roster.lock().unwrap().peers.len() + 1,
+            ROOM_CAPACITY
+        );
+        let header = match self.title.lock().unwrap().as_ref() {
+            Some(title) => format!("== {} · {} ==", title, occupancy),
+            None => format!("== {} ==", occupancy),
+        };
+        // A long title is cut rather than wrapped, the header stays one row
+        screen.push_str(&wrap_line(&header, cols).swap_remove(0));
+        screen.push('\n');
+
+        // Only what fits above the input line, so redraws cost the same all session. Every
+        // message takes at least one row, so the last `rows` messages are always enough
+        let lines: Vec<String> = self
+            .messages
+            .lock()
+            .unwrap()
+            .last(rows)
+            .flat_map(|msg| wrap_line(msg, cols))
+            .collect();
+        for line in &lines[lines.len().saturating_sub(rows)..] {
+            screen.push_str(line);
+            screen.push('\n');
         }
         
-        print!("> {}", self.current_input.lock().unwrap());
-        
+        // The editor redraws its prompt and whatever is being typed below the screen
+        if let Some(printer) = self.printer.lock().unwrap().as_mut() {
+            let _ = printer.print(screen);
+            return;
+        }
+        print!("{}", screen);
         io::stdout().flush().unwrap();
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let endpoint = Endpoint::builder().discovery_n0().bind().await?;
-    
-    let ui = TerminalUI::new();
-    //ui.add_message(format!("> our node id: {}", endpoint.node_id()));
-
-    let gossip = Gossip::builder().spawn(endpoint.clone());
-    let _router = iroh::protocol::Router::builder(endpoint.clone())
-        .accept(GOSSIP_ALPN, gossip.clone())
-        .spawn();
-
-    let (topic_id, node_ids) = match cli.commands {
-        Commands::Open => (TopicId::from_bytes(rand::random()), Vec::new()),
-        Commands::Join { ticket } => {
-            let ticket = Ticket::from_code_or_full(&ticket)?;
-            //ui.add_message(format!("> joining topic: {}", ticket.topic));
-            
-            for node in &ticket.nodes {
-                endpoint.add_node_addr(NodeAddr::new(node.node_id)
-                    .with_direct_addresses(node.direct_addresses.clone()))?;
-            }
-            
-            (ticket.topic, ticket.nodes.iter().map(|n| n.node_id).collect())
-        }
-    };
-
-    let ticket = {
-        let me = endpoint.node_addr().initialized().await;
-        Ticket {
-            topic: topic_id,
-            nodes: vec![CompactNodeInfo {
-                node_id: me.node_id,
-                direct_addresses: me.direct_addresses.into_iter().collect(),
-            }],
-        }
+pub async fn run(
+    endpoint: Endpoint,
+    sender: GossipSender,
+    receiver: GossipReceiver,
+    room_code: String,
+    events: EventSink,
+    activity: Activity,
+    options: ChatOptions,
+) -> Result<()> {
+    let log = match &options.log_file {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open log file {}", path.display()))?,
+        ),
+        None => None,
     };
-    
-    ui.add_message(format!("Room code! {}", ticket.to_short_code()?));
-    //ui.add_message(format!("> full ticket: {}", ticket));
-    //ui.add_message("> share either the 8-character code or the full ticket!".to_string());
-
-    ui.add_message(if node_ids.is_empty() {
-        "waiting for peers...".to_string()
-    } else {
-        "connecting to peers...".to_string()
-    });
-    
-    let (sender, receiver) = gossip
-        .subscribe_and_join(topic_id, node_ids)
-        .await?
-        .split();
+    let ui = TerminalUI::new(options.scrollback, log);
+    let me = options.nick.clone().unwrap_or_else(|| "you".to_string());
+    ui.add_message(format!("Room code! {}", room_code));
     ui.add_message("successfully connected!".to_string());
     ui.add_message("-----------------------".to_string());
 
     sender.broadcast(Message::new(MessageBody::AboutMe {
         from: endpoint.node_id(),
+        proof: options.proof,
+        view: None,
+        caps: Capabilities::text_only(),
+        nick: options.nick.clone(),
     }).to_vec().into()).await?;
+    if let Some(title) = &options.title {
+        ui.set_title(title.clone());
+        send_room_info(&sender, endpoint.node_id(), title).await;
+    }
 
     let ui_clone = ui.clone();
-    tokio::spawn(async move {
-        subscribe_loop(receiver, ui_clone).await
-    });
+    let sender_clone = sender.clone();
+    let my_id = endpoint.node_id();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let subscribe_task = spawn_until_shutdown(
+        subscribe_loop(
+            receiver,
+            sender_clone,
+            my_id,
+            RoomSettings {
+                // Only the host enforces the password, joiners just present theirs
+                required_proof: if options.is_host { options.proof } else { None },
+                title: options.title,
+                rejoin: options.rejoin,
+                peer_timeout: options.peer_timeout,
+            },
+            activity.clone(),
+            ui_clone,
+            events,
+        ),
+        shutdown_rx,
+    );
 
     let (line_tx, mut line_rx) = mpsc::channel(1);
     let ui_clone = ui.clone();
     std::thread::spawn(move || input_loop(line_tx, ui_clone));
 
-    while let Some(text) = line_rx.recv().await {
+    let idle = idle_timeout(activity.clone(), options.idle_timeout_secs);
+    tokio::pin!(idle);
+    let mut idle_ended = None;
+    loop {
+        let text = tokio::select! {
+            text = line_rx.recv() => match text {
+                Some(text) => text,
+                None => break,
+            },
+            _ = tokio::signal::ctrl_c() => break,
+            // The editor holds the terminal until the next line, so the room is closed right
+            // away and the exit waits for Enter
+            e = &mut idle, if idle_ended.is_none() => {
+                ui.close(&e.root_cause().to_string());
+                if options.is_host {
+                    close_room(&sender, endpoint.node_id()).await;
+                }
+                idle_ended = Some(e);
+                continue;
+            }
+        };
         let text = text.trim();
-        if !text.is_empty() {
-            sender.broadcast(Message::new(MessageBody::Message {
-                from: endpoint.node_id(),
-                text: text.to_string(),
-            }).to_vec().into()).await?;
+        if text.is_empty() {
+            continue;
+        }
+        if text == "/who" {
+            ui.show_roster();
+            continue;
+        }
+        let message = Message::new(MessageBody::Message {
+            from: endpoint.node_id(),
+            text: text.to_string(),
+        });
+        let bytes = message.to_vec();
+        if bytes.len() > MAX_MESSAGE_SIZE {
+            ui.add_message(format!(
+                "message too large, {} bytes (limit is {} bytes)",
+                bytes.len(),
+                MAX_MESSAGE_SIZE
+            ));
+            continue;
+        }
+        match sender.broadcast(bytes.into()).await {
+            Ok(()) => ui.add_outgoing(format!("{}: {}", me, text), message.nonce),
+            Err(e) => ui.add_message(format!("failed to send message: {}", e)),
+        }
+    }
+
+    let _ = shutdown_tx.send(true);
+    if let Ok(Err(e)) = subscribe_task.await {
+        // The room ended for us, main maps it to its exit code now that the editor is dropped
+        if e.downcast_ref::<Failure>().is_some() {
+            return Err(e);
         }
-        ui.add_message(format!("you: {}", text));
+        eprintln!("Receive loop ended with an error: {}", e);
+    }
+    if let Some(e) = idle_ended {
+        return Err(e);
+    }
+    if options.is_host {
+        close_room(&sender, endpoint.node_id()).await;
     }
-    
     Ok(())
 }
 
-async fn subscribe_loop(mut receiver: GossipReceiver, ui: TerminalUI) -> Result<()> {
-    while let Some(event) = receiver.try_next().await? {
+async fn subscribe_loop(
+    mut receiver: GossipReceiver,
+    sender: GossipSender,
+    my_node_id: NodeId,
+    room: RoomSettings,
+    activity: Activity,
+    ui: TerminalUI,
+    events: EventSink,
+) -> Result<()> {
+    let RoomSettings { required_proof, title, rejoin, peer_timeout } = room;
+    let mut neighbors = Neighbors::default();
+    // Peers whose AboutMe carried the right password, only tracked when one is required
+    let mut admitted = HashSet::new();
+    let mut rejected = HashSet::new();
+    let mut room_full_sent = HashMap::new();
+    let mut prune = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        let event = tokio::select! {
+            event = receiver.try_next() => match event? {
+                Some(event) => event,
+                None => break,
+            },
+            _ = prune.tick(), if peer_timeout.is_some() => {
+                let gone = ui.roster.lock().unwrap().prune(peer_timeout.unwrap_or_default());
+                for label in gone {
+                    ui.add_message(format!("{} went silent and left", label));
+                }
+                continue;
+            }
+        };
+        if let Some(notice) = neighbors.update(&event, &rejoin, &sender).await {
+            ui.add_message(notice);
+        }
+        if let Event::NeighborDown(peer) = &event {
+            let label = ui.label(peer);
+            if ui.roster.lock().unwrap().peers.remove(peer).is_some() {
+                ui.add_message(format!("{} left", label));
+            }
+        }
         if let Event::Received(msg) = event {
-            match Message::from_bytes(&msg.content)?.body {
-                MessageBody::AboutMe { from } => {
-                    ui.add_message(format!("{} has joined!", from.fmt_short()));
+            activity.touch();
+            let message = match Message::from_bytes(&msg.content) {
+                Ok(message) => message,
+                Err(e) => {
+                    eprintln!("Failed to decode message: {}", e);
+                    events.emit(AppEvent::Error {
+                        message: format!("failed to decode message: {}", e),
+                    });
+                    continue;
+                }
+            };
+            // Password rooms only hear from peers that passed the check in their AboutMe
+            let from = message.body.sender();
+            if required_proof.is_some()
+                && from != my_node_id
+                && !admitted.contains(&from)
+                && !matches!(message.body, MessageBody::AboutMe { .. })
+            {
+                continue;
+            }
+            // Same rule as the video loop: anyone showing up once the roster is full gets RoomFull
+            let joining = match &message.body {
+                MessageBody::AboutMe { proof, .. } => required_proof.is_none() || *proof == required_proof,
+                MessageBody::Message { .. } | MessageBody::KeepAlive { .. } | MessageBody::VideoFrame { .. } => true,
+                _ => false,
+            };
+            if joining && from != my_node_id && ui.roster.lock().unwrap().is_full_without(&from) {
+                if rejected.insert(from) {
+                    ui.add_message(format!("{} tried to join but the room is full", short_id(&from)));
+                    events.emit(AppEvent::PeerRejected { peer: from.to_string() });
+                }
+                send_room_full(&sender, my_node_id, from, &mut room_full_sent).await;
+                continue;
+            }
+            match message.body {
+                MessageBody::AboutMe { from, proof, .. } if required_proof.is_some() && proof != required_proof => {
+                    if from != my_node_id {
+                        ui.add_message(format!("{} tried to join with a wrong password", short_id(&from)));
+                        events.emit(AppEvent::PeerRejected { peer: from.to_string() });
+                        let _ = sender.broadcast(Message::new(MessageBody::AuthFailed {
+                            from: my_node_id,
+                            target: from,
+                        }).to_vec().into()).await;
+                    }
+                }
+                MessageBody::AboutMe { from, nick, .. } => {
+                    admitted.insert(from);
+                    // Sent again on every rejoin, and a keepalive may have put the peer on the roster first
+                    if ui.roster.lock().unwrap().seen(from, nick) {
+                        ui.add_message(format!("{} has joined!", ui.label(&from)));
+                        events.emit(AppEvent::PeerJoined { peer: from.to_string() });
+                    }
+                    if let Some(title) = &title {
+                        send_room_info(&sender, my_node_id, title).await;
+                    }
+                }
+                MessageBody::RoomInfo { from, title } => {
+                    if from != my_node_id && ui.set_title(title.clone()) {
+                        ui.add_message(format!("connected to: {}", title));
+                    }
                 }
                 MessageBody::Message { from, text } => {
-                    ui.add_message(format!("{}: {}", from.fmt_short(), text));
+                    if ui.roster.lock().unwrap().seen(from, None) {
+                        ui.add_message(format!("{} is here", ui.label(&from)));
+                    }
+                    ui.add_message(format!("{}: {}", ui.label(&from), text));
+                    let _ = sender.broadcast(Message::new(MessageBody::Ack {
+                        from: my_node_id,
+                        nonce: message.nonce,
+                    }).to_vec().into()).await;
+                }
+                MessageBody::Ack { from, nonce } => {
+                    if from != my_node_id {
+                        ui.mark_delivered(&nonce);
+                    }
+                }
+                // These end the session through `run`, which exits once the editor is gone
+                MessageBody::RoomFull { from, target } => {
+                    if from != my_node_id && target == my_node_id {
+                        ui.close("the room is full");
+                        events.emit(AppEvent::RoomFull);
+                        return Err(anyhow::anyhow!("only {} people allowed per room", ROOM_CAPACITY)).context(Failure::RoomFull);
+                    }
+                }
+                MessageBody::AuthFailed { from, target } => {
+                    if from != my_node_id && target == my_node_id {
+                        ui.close("wrong password for this room");
+                        return Err(anyhow::anyhow!("wrong password for this room")).context(Failure::WrongPassword);
+                    }
+                }
+                MessageBody::Kicked { from, target } => {
+                    if from != my_node_id && target == my_node_id {
+                        ui.close("the host removed you from the room");
+                        events.emit(AppEvent::Kicked);
+                        return Err(anyhow::anyhow!("the host removed you from the room")).context(Failure::Kicked);
+                    }
+                }
+                MessageBody::RoomClosed { from } => {
+                    if from != my_node_id {
+                        ui.close("host closed the room");
+                        events.emit(AppEvent::RoomClosed);
+                        return Err(anyhow::anyhow!("host closed the room")).context(Failure::RoomClosed);
+                    }
+                }
+                MessageBody::Presence { from, state } => {
+                    if from != my_node_id {
+                        ui.add_message(format!("> {} {}", ui.label(&from), state.describe()));
+                    }
                 }
+                MessageBody::KeepAlive { from } => {
+                    if from != my_node_id && ui.roster.lock().unwrap().seen(from, None) {
+                        ui.add_message(format!("{} is here", ui.label(&from)));
+                    }
+                }
+                // Video peers count as present while their frames keep coming
+                MessageBody::VideoFrame { from, .. } => {
+                    if from != my_node_id && ui.roster.lock().unwrap().seen(from, None) {
+                        ui.add_message(format!("{} is here", ui.label(&from)));
+                    }
+                }
+                MessageBody::ViewSize { .. } | MessageBody::RequestFullFrame { .. } => {}
             }
         }
     }
     Ok(())
 }
 
+// Line editing and history come from rustyline; Ctrl-C and Ctrl-D end the session by
+// dropping `line_tx`, since the editor's raw mode swallows the signal
 fn input_loop(line_tx: mpsc::Sender<String>, ui: TerminalUI) -> Result<()> {
-    let mut buffer = String::new();
+    let mut editor = DefaultEditor::new()?;
+    if let Ok(printer) = editor.create_external_printer() {
+        ui.set_printer(Box::new(printer));
+    }
     loop {
-        std::io::stdin().read_line(&mut buffer)?;
-        ui.update_input(buffer.clone());
-        line_tx.blocking_send(buffer.clone())?;
-        buffer.clear();
-        ui.update_input(buffer.clone());
+        if ui.is_closed() {
+            return Ok(());
+        }
+        match editor.readline("> ") {
+            Ok(_) if ui.is_closed() => return Ok(()),
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = editor.add_history_entry(line.as_str());
+                }
+                line_tx.blocking_send(line)?;
+            }
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
     }
+}
+
+// Splits a line into rows no wider than `width` columns. Breaks only between grapheme clusters
+// and measures them by display width, so wide CJK and emoji count as two columns. Color
+// escapes take no room and stay in the row they were written in
+fn wrap_line(line: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    let mut row = String::new();
+    let mut used = 0;
+    let mut in_escape = false;
+    for grapheme in line.graphemes(true) {
+        if grapheme == "\x1B" || in_escape {
+            // Same rule as `strip_ansi`: a CSI sequence runs up to and including its final letter
+            in_escape = grapheme == "\x1B" || !grapheme.chars().all(|c| c.is_ascii_alphabetic());
+            row.push_str(grapheme);
+            continue;
+        }
+        let columns = grapheme.width();
+        if used + columns > width && used > 0 {
+            rows.push(std::mem::take(&mut row));
+            used = 0;
+        }
+        row.push_str(grapheme);
+        used += columns;
+    }
+    rows.push(row);
+    rows
+}
+
+fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1B' {
+            // Skip a CSI sequence up to and including its final letter
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn utc_timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        (rem % 3_600) / 60,
+        rem % 60
+    )
 }
\ No newline at end of file
@@ -0,0 +1,197 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::frame::{frames_differ, reduce_frame_size, send_dimensions};
+
+// Packed RGB, three bytes per pixel, rows back to back with no padding.
+// Serializes exactly like the old inline `frame_data, width, height` fields did.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Frame {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Frame {
+    pub fn new(data: Vec<u8>, width: u32, height: u32) -> Result<Self> {
+        let frame = Self { data, width, height };
+        frame.validate()?;
+        Ok(frame)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(anyhow!("frame has zero size ({}x{})", self.width, self.height));
+        }
+        let expected = (self.width as usize)
+            .checked_mul(self.height as usize)
+            .and_then(|pixels| pixels.checked_mul(3))
+            .ok_or_else(|| anyhow!("frame size {}x{} overflows", self.width, self.height))?;
+        if self.data.len() != expected {
+            return Err(anyhow!(
+                "frame is {}x{} but carries {} bytes, expected {}",
+                self.width,
+                self.height,
+                self.data.len(),
+                expected
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+
+    // Fits raw capture output inside `max_w` x `max_h` without borrowing the camera buffer
+    pub fn scaled_from(data: &[u8], width: u32, height: u32, max_w: u32, max_h: u32) -> Self {
+        let (new_w, new_h) = send_dimensions(width, height, max_w, max_h);
+        Self {
+            data: reduce_frame_size(data, width, height, new_w, new_h),
+            width: new_w,
+            height: new_h,
+        }
+    }
+
+    pub fn scaled(&self, max_w: u32, max_h: u32) -> Self {
+        Self::scaled_from(&self.data, self.width, self.height, max_w, max_h)
+    }
+
+    // Covers the size as well, so a frame whose header got mangled doesn't match either
+    pub fn checksum(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.width.to_le_bytes());
+        hasher.update(&self.height.to_le_bytes());
+        hasher.update(&self.data);
+        *hasher.finalize().as_bytes()
+    }
+
+    pub fn differs_from(&self, other: &Frame, threshold_percent: u8) -> bool {
+        (self.width, self.height) != (other.width, other.height)
+            || frames_differ(&self.data, &other.data, threshold_percent)
+    }
+
+    // Red checkerboard with a white dot, sent in place of video while the camera is failing
+    pub fn placeholder() -> Self {
+        let width = 640u32;
+        let height = 480u32;
+        let mut data = Vec::with_capacity((width * height * 3) as usize);
+
+        let center_x = width / 2;
+        let center_y = height / 2;
+
+        for y in 0..height {
+            for x in 0..width {
+                let dx = (x as i32 - center_x as i32).abs();
+                let dy = (y as i32 - center_y as i32).abs();
+                let dist = ((dx * dx + dy * dy) as f64).sqrt();
+
+                if dist < 50.0 {
+                    data.extend_from_slice(&[255, 255, 255]);
+                } else if (x / 40) % 2 == (y / 40) % 2 {
+                    data.extend_from_slice(&[180, 40, 40]);
+                } else {
+                    data.extend_from_slice(&[120, 20, 20]);
+                }
+            }
+        }
+
+        Self { data, width, height }
+    }
+}
+
+// Frames sent as plain `Frame` RGB, which every video peer has to understand
+pub const ENCODING_RAW: u32 = 1 << 0;
+
+// What a peer can handle, exchanged in `AboutMe` so each side only uses features both support
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    // Bitset of the `ENCODING_*` flags this peer can decode
+    pub encodings: u32,
+    pub audio: bool,
+    // Largest frame this peer sends or wants to receive
+    pub max_resolution: (u32, u32),
+}
+
+impl Capabilities {
+    pub fn video(max_w: u32, max_h: u32) -> Self {
+        Self {
+            encodings: ENCODING_RAW,
+            audio: false,
+            max_resolution: (max_w, max_h),
+        }
+    }
+
+    // Text-only clients decode nothing, so video senders can leave them alone
+    pub fn text_only() -> Self {
+        Self {
+            encodings: 0,
+            audio: false,
+            max_resolution: (0, 0),
+        }
+    }
+
+    pub fn supports(&self, encoding: u32) -> bool {
+        self.encodings & encoding == encoding
+    }
+
+    pub fn common(&self, other: &Capabilities) -> Self {
+        Self {
+            encodings: self.encodings & other.encodings,
+            audio: self.audio && other.audio,
+            max_resolution: (
+                self.max_resolution.0.min(other.max_resolution.0),
+                self.max_resolution.1.min(other.max_resolution.1),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_sized_frames_are_rejected() {
+        for (width, height) in [(0, 0), (0, 4), (4, 0)] {
+            let frame = Frame { data: Vec::new(), width, height };
+            assert!(!frame.is_valid(), "{width}x{height} passed validation");
+            assert!(Frame::new(Vec::new(), width, height).is_err());
+        }
+    }
+
+    #[test]
+    fn data_must_match_the_dimensions() {
+        assert!(Frame::new(vec![0; 2 * 2 * 3], 2, 2).is_ok());
+        assert!(Frame::new(vec![0; 2 * 2 * 3 - 1], 2, 2).is_err());
+        assert!(Frame::new(vec![0; 2 * 2 * 3 + 3], 2, 2).is_err());
+        assert!(Frame::new(Vec::new(), 2, 2).is_err());
+        assert!(Frame::new(vec![0; 3], u32::MAX, u32::MAX).is_err());
+    }
+
+    #[test]
+    fn scaling_degenerate_frames_still_gives_a_valid_frame() {
+        // Zero-sized source: nothing to sample, but no panic and a usable 1x1 result
+        let empty = Frame { data: Vec::new(), width: 0, height: 0 };
+        assert!(empty.scaled(64, 48).is_valid());
+
+        // Short data is padded with black instead of indexing past the end
+        let short = Frame { data: vec![255; 3], width: 4, height: 4 };
+        let scaled = short.scaled(2, 2);
+        assert!(scaled.is_valid());
+        assert_eq!((scaled.width, scaled.height), (2, 2));
+
+        // A zero-sized target rounds up to a single pixel
+        let pixel = Frame::new(vec![10, 20, 30], 1, 1).unwrap();
+        assert_eq!(pixel.scaled(0, 0), pixel);
+    }
+
+    #[test]
+    fn placeholder_is_valid_and_scales_down_to_a_pixel() {
+        let placeholder = Frame::placeholder();
+        assert!(placeholder.is_valid());
+        let tiny = placeholder.scaled(1, 1);
+        assert!(tiny.is_valid());
+        assert_eq!((tiny.width, tiny.height), (1, 1));
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+// Defaults for command line flags, read from `<config dir>/p2p-cli/config.toml`.
+// Every key is optional; flags given on the command line always win.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub nick: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub gamma: Option<f32>,
+    pub relay_url: Option<String>,
+    pub identity: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("p2p-cli").join("config.toml"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read config {}", path.display()))
+            }
+        };
+        toml::from_str(&content).with_context(|| format!("invalid config {}", path.display()))
+    }
+}
+
+// Which camera index and format opened last time for each device, so the next start can skip
+// the probe. Lives next to config.toml rather than in it, since that file is edited by hand.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraCache {
+    pub devices: HashMap<String, CachedFormat>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedFormat {
+    pub camera_index: u32,
+    // Position in the format list `CameraCapture::new` probes
+    pub format: usize,
+}
+
+impl CameraCache {
+    fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("p2p-cli").join("camera.toml"))
+    }
+
+    // A missing or broken cache only costs a full probe, so it is never an error
+    pub fn load() -> Self {
+        Self::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|content| toml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn remember(&mut self, device: String, choice: CachedFormat) -> Result<()> {
+        if self.devices.get(&device) == Some(&choice) {
+            return Ok(());
+        }
+        self.devices.insert(device, choice);
+        let Some(path) = Self::path() else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, toml::to_string(self)?)
+            .with_context(|| format!("failed to write camera cache {}", path.display()))
+    }
+}
@@ -1,7 +1,160 @@
+use std::fmt::Write as _;
 use std::io::{self, Write, BufWriter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use anyhow::Result;
 use colored::control;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FillMode {
+    /// Keep the aspect ratio and cut off whatever overflows the terminal
+    Crop,
+    /// Scale each axis independently to cover the whole terminal
+    Stretch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Palette {
+    /// Five-step ramp: ` .:#@`
+    Standard,
+    /// Fine-grained ramp with many shades, for larger terminals
+    Dense,
+    /// Shade blocks (░▒▓█), high contrast for low vision
+    Blocks,
+}
+
+impl Palette {
+    // Darkest to brightest
+    fn ramp(self) -> &'static [char] {
+        match self {
+            Palette::Standard => &[' ', '.', ':', '#', '@'],
+            Palette::Dense => &[
+                ' ', '.', ',', ':', ';', 'i', 'r', 's', 'X', 'A', '2', '5', '3', 'h', 'M', 'H', 'G', 'S', '#',
+                '9', 'B', '&', '@',
+            ],
+            Palette::Blocks => &[' ', '░', '▒', '▓', '█'],
+        }
+    }
+}
+
+// Which of `levels` ramp entries a brightness falls on, splitting 0..=255 into equal bands
+fn ramp_index(brightness: u8, levels: usize) -> usize {
+    brightness as usize * levels / 256
+}
+
+#[derive(Debug, Clone)]
+pub struct DisplayOptions {
+    pub gamma: f32,
+    pub ascii: bool,
+    pub dither: bool,
+    pub fill: Option<FillMode>,
+    pub palette: Palette,
+    // Video area in terminal cells that ignores the real terminal size and its resizes
+    pub fixed_size: Option<(usize, usize)>,
+    // Bits kept per truecolor channel; fewer make neighbouring cells match and skip their escapes
+    pub color_bits: u8,
+    // 24-bit escapes when set, otherwise the nearest of the 256 xterm colors
+    pub truecolor: bool,
+    // Leaves the cursor visible while video plays, for debugging
+    pub show_cursor: bool,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        Self {
+            gamma: 1.0,
+            ascii: false,
+            dither: false,
+            fill: None,
+            palette: Palette::Standard,
+            fixed_size: None,
+            color_bits: 8,
+            truecolor: supports_truecolor(),
+            show_cursor: false,
+        }
+    }
+}
+
+// Set while a display has the cursor hidden, so exits that skip `Drop` know to bring it back
+static CURSOR_HIDDEN: AtomicBool = AtomicBool::new(false);
+
+const CAPTION_TTL: Duration = Duration::from_secs(5);
+const OVERLAY_TTL: Duration = Duration::from_secs(8);
+
+// Below this the image would be a handful of blocks, so a notice is shown instead
+const MIN_TERM_W: usize = 20;
+const MIN_TERM_H: usize = 8;
+
+const BAYER_4X4: [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+// Stands in for the peer's picture when there is no live video to show
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Placeholder {
+    // The peer stopped sending on purpose
+    CameraOff { peer: String },
+    // The peer dropped out of the room, `silent_secs` after their last frame
+    Disconnected { peer: String, silent_secs: u64 },
+}
+
+impl Placeholder {
+    fn lines(&self) -> Vec<String> {
+        match self {
+            Placeholder::CameraOff { peer } => vec![
+                "[ camera off ]".to_string(),
+                String::new(),
+                format!("{} turned their camera off", peer),
+            ],
+            Placeholder::Disconnected { peer, silent_secs } => vec![
+                "[ disconnected ]".to_string(),
+                String::new(),
+                format!("{} dropped out, no frames for {}s", peer, silent_secs),
+            ],
+        }
+    }
+}
+
+// Where the overlay box lands on the block grid, with its text already laid out
+struct OverlayBox {
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+    rows: Vec<Vec<char>>,
+}
+
+impl OverlayBox {
+    fn new(lines: &[String], disp_w: usize, disp_h: usize) -> Self {
+        let w = (lines.iter().map(|line| line.chars().count()).max().unwrap_or(0) + 4).min(disp_w);
+        let h = (lines.len() + 2).min(disp_h);
+        let rows = lines
+            .iter()
+            .map(|line| line.chars().take(w.saturating_sub(4)).collect())
+            .collect();
+        Self {
+            x: (disp_w - w) / 2,
+            y: (disp_h - h) / 2,
+            w,
+            h,
+            rows,
+        }
+    }
+
+    // `None` outside the box, otherwise the character to draw there (a space where there's no text)
+    fn cell(&self, x: usize, y: usize) -> Option<char> {
+        if x < self.x || x >= self.x + self.w || y < self.y || y >= self.y + self.h {
+            return None;
+        }
+        let row = (y - self.y).checked_sub(1).and_then(|row| self.rows.get(row));
+        let text = row.and_then(|row| (x - self.x).checked_sub(2).and_then(|col| row.get(col)));
+        Some(text.copied().unwrap_or(' '))
+    }
+}
+
 pub struct TerminalDisplay {
     cam_w: u32,
     cam_h: u32,
@@ -9,91 +162,370 @@ pub struct TerminalDisplay {
     term_h: usize,
     disp_w: usize,
     disp_h: usize,
-    scale: u32,
+    // Source pixels per display column / per half-block row, plus the top-left of the sampled region
+    step_x: f32,
+    step_y: f32,
+    crop_x: usize,
+    crop_y: usize,
+    fill: Option<FillMode>,
+    fixed_size: Option<(usize, usize)>,
     h_pad: usize,
     v_pad: usize,
+    too_small: bool,
     buf: String,
     writer: BufWriter<std::io::Stdout>,
     redraw: bool,
     supports_color: bool,
+    truecolor: bool,
+    gamma_lut: [u8; 256],
+    dither: bool,
+    color_mask: u8,
+    ramp: &'static [char],
+    caption: Option<(String, Instant)>,
+    status: Option<String>,
+    presence: Option<String>,
+    occupancy: Option<String>,
+    last_frame: Vec<u8>,
+    empty_frame_logged: bool,
+    // Room code and hints shown until the first frame arrives
+    lobby: Option<(String, Vec<String>)>,
+    placeholder: Option<Placeholder>,
+    overlay: Option<(Vec<String>, Instant)>,
 }
 
 impl TerminalDisplay {
-    pub fn new(cam_w: u32, cam_h: u32) -> Self {
+    pub fn new(cam_w: u32, cam_h: u32, options: &DisplayOptions) -> Self {
         // Initialize colored crate for Windows support
         #[cfg(windows)]
         let _ = control::set_virtual_terminal(true);
         
-        let supports_color = control::SHOULD_COLORIZE.should_colorize();
+        let supports_color = !options.ascii && control::SHOULD_COLORIZE.should_colorize();
         
         let (term_w, term_h) = term_size();
         
-        let max_w = term_w.saturating_sub(2);
-        let max_h = term_h.saturating_sub(3);
-        
-        let scale_x = (cam_w as f32 / max_w as f32).ceil() as u32;
-        let scale_y = (cam_h as f32 / (max_h * 2) as f32).ceil() as u32;
-        let scale = scale_x.max(scale_y).max(2);
-        
-        let disp_w = (cam_w / scale).max(1) as usize;
-        let disp_h = (cam_h / (scale * 2)).max(1) as usize;
-        
-        let h_pad = (term_w.saturating_sub(disp_w)) / 2;
-        let v_pad = (term_h.saturating_sub(disp_h).saturating_sub(2)) / 2;
-        
-        let buf_size = (disp_w * disp_h * 50) + 1000;
-        
-        if supports_color {
+        if supports_color && !options.show_cursor {
             print!("\x1B[?25l");
+            CURSOR_HIDDEN.store(true, Ordering::SeqCst);
         }
         io::stdout().flush().unwrap();
         
-        Self {
+        let mut display = Self {
             cam_w,
             cam_h,
             term_w,
             term_h,
-            disp_w,
-            disp_h,
-            scale,
-            h_pad,
-            v_pad,
-            buf: String::with_capacity(buf_size),
+            disp_w: 1,
+            disp_h: 1,
+            step_x: 1.0,
+            step_y: 1.0,
+            crop_x: 0,
+            crop_y: 0,
+            fill: options.fill,
+            fixed_size: options.fixed_size,
+            h_pad: 0,
+            v_pad: 0,
+            too_small: false,
+            buf: String::new(),
             writer: BufWriter::with_capacity(32768, io::stdout()),
             redraw: true,
             supports_color,
+            truecolor: options.truecolor,
+            gamma_lut: gamma_lut(options.gamma),
+            dither: options.dither,
+            color_mask: 0xFFu8 << (8 - options.color_bits.clamp(1, 8)),
+            ramp: options.palette.ramp(),
+            caption: None,
+            status: None,
+            presence: None,
+            occupancy: None,
+            last_frame: Vec::new(),
+            empty_frame_logged: false,
+            lobby: None,
+            placeholder: None,
+            overlay: None,
+        };
+        display.calc_layout();
+        display.buf.reserve((display.disp_w * display.disp_h * 50) + 1000);
+        display
+    }
+
+    // A display with no video yet, so there is something on screen while waiting for the peer
+    pub fn lobby(room_code: &str, hints: Vec<String>, options: &DisplayOptions) -> Self {
+        let mut display = Self::new(0, 0, options);
+        display.lobby = Some((room_code.to_string(), hints));
+        display
+    }
+
+    pub fn in_lobby(&self) -> bool {
+        self.lobby.is_some()
+    }
+
+    pub fn render_lobby(&mut self, waited: Duration) -> Result<()> {
+        let Some((room_code, hints)) = &self.lobby else {
+            return Ok(());
+        };
+        const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+        let spinner = SPINNER[(waited.as_millis() / 120) as usize % SPINNER.len()];
+        let mut lines = vec![
+            format!("room code: {}", room_code),
+            String::new(),
+            format!("{} waiting for peer... {}s", spinner, waited.as_secs()),
+            String::new(),
+        ];
+        lines.extend(hints.iter().cloned());
+        if let Some((text, shown_at)) = &self.caption {
+            if shown_at.elapsed() < CAPTION_TTL {
+                lines.push(String::new());
+                lines.push(text.clone());
+            }
+        }
+        self.render_card(&lines)
+    }
+
+    // Replaces the video with a labeled card until the next frame, `None` goes back to the last picture
+    pub fn set_placeholder(&mut self, placeholder: Option<Placeholder>) -> Result<()> {
+        if self.placeholder == placeholder {
+            return Ok(());
+        }
+        let same_kind = match (&self.placeholder, &placeholder) {
+            (Some(old), Some(new)) => std::mem::discriminant(old) == std::mem::discriminant(new),
+            _ => false,
+        };
+        let was_showing = self.placeholder.is_some();
+        self.placeholder = placeholder;
+        // A counter ticking up redraws in place, anything else starts from a clear screen
+        if !same_kind {
+            self.redraw = true;
+        }
+        if self.placeholder.is_some() {
+            return self.render_placeholder();
+        }
+        if !was_showing {
+            return Ok(());
+        }
+        self.render_last_frame()
+    }
+
+    // Text drawn over a dimmed middle of the video, e.g. the keybinding help; `None` removes it
+    pub fn set_overlay(&mut self, lines: Option<Vec<String>>) -> Result<()> {
+        self.overlay = lines.map(|lines| (lines, Instant::now()));
+        self.render_last_frame()
+    }
+
+    pub fn has_overlay(&self) -> bool {
+        self.overlay.is_some()
+    }
+
+    pub fn overlay_expired(&self) -> bool {
+        self.overlay.as_ref().is_some_and(|(_, shown_at)| shown_at.elapsed() >= OVERLAY_TTL)
+    }
+
+    // Re-renders what is on screen without waiting for the next frame to arrive
+    fn render_last_frame(&mut self) -> Result<()> {
+        if self.last_frame.is_empty() || self.placeholder.is_some() {
+            return Ok(());
+        }
+        let frame = std::mem::take(&mut self.last_frame);
+        let result = self.render_blocks(&frame);
+        self.last_frame = frame;
+        result
+    }
+
+    fn render_placeholder(&mut self) -> Result<()> {
+        let Some(placeholder) = &self.placeholder else {
+            return Ok(());
+        };
+        let mut lines = placeholder.lines();
+        if let Some((text, shown_at)) = &self.caption {
+            if shown_at.elapsed() < CAPTION_TTL {
+                lines.push(String::new());
+                lines.push(text.clone());
+            }
+        }
+        let status = self.status_line();
+        if !status.is_empty() {
+            lines.push(String::new());
+            lines.push(status);
+        }
+        self.render_card(&lines)
+    }
+
+    // Centers a few lines of text on an otherwise blank screen
+    fn render_card(&mut self, lines: &[String]) -> Result<()> {
+        // Without cursor control each tick would scroll the terminal, so it's drawn once
+        if !self.supports_color && !self.redraw {
+            return Ok(());
+        }
+        self.buf.clear();
+        if self.supports_color {
+            self.buf.push_str(if self.redraw { "\x1B[2J\x1B[H" } else { "\x1B[H" });
+        }
+        self.redraw = false;
+        for _ in 0..self.term_h.saturating_sub(lines.len()) / 2 {
+            self.buf.push('\n');
+        }
+        for line in lines {
+            let width = line.chars().count().min(self.term_w);
+            for _ in 0..(self.term_w - width) / 2 {
+                self.buf.push(' ');
+            }
+            self.buf.extend(line.chars().take(width));
+            if self.supports_color {
+                self.buf.push_str("\x1B[K");
+            }
+            self.buf.push('\n');
+        }
+        // Clears anything printed below since the last tick
+        if self.supports_color {
+            self.buf.push_str("\x1B[J");
+        }
+        self.writer.write_all(self.buf.as_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.cam_w, self.cam_h)
+    }
+
+    // Switches to a new source resolution in place, keeping the writer, buffers and hidden cursor
+    pub fn reconfigure(&mut self, cam_w: u32, cam_h: u32) {
+        if (cam_w, cam_h) == (self.cam_w, self.cam_h) {
+            return;
         }
+        self.cam_w = cam_w;
+        self.cam_h = cam_h;
+        self.calc_layout();
+        // The stored frame has the old stride, so a resize must not re-render it
+        self.last_frame.clear();
+        self.redraw = true;
+    }
+
+    pub fn set_caption(&mut self, text: String) {
+        self.caption = Some((text, Instant::now()));
+    }
+
+    pub fn set_status(&mut self, status: Option<String>) {
+        self.status = status;
+    }
+
+    // Shown ahead of the status line, e.g. when the peer is away or has their camera off
+    pub fn set_presence(&mut self, presence: Option<String>) {
+        self.presence = presence;
+    }
+
+    // Kept at the front of the status line for the whole call, e.g. "2/2 in room"
+    pub fn set_occupancy(&mut self, occupancy: String) {
+        self.occupancy = Some(occupancy);
     }
 
     pub fn show_frame(&mut self, frame_bytes: &[u8]) -> Result<()> {
-        let (new_w, new_h) = term_size();
-        if new_w != self.term_w || new_h != self.term_h {
-            self.term_w = new_w;
-            self.term_h = new_h;
-            self.calc_layout();
+        if frame_bytes.is_empty() || self.cam_w == 0 || self.cam_h == 0 {
+            if !self.empty_frame_logged {
+                self.empty_frame_logged = true;
+                eprintln!("Dropping empty or zero-sized frame");
+            }
+            return Ok(());
+        }
+        self.lobby = None;
+        if self.placeholder.take().is_some() {
             self.redraw = true;
         }
-        
+        self.last_frame.clear();
+        self.last_frame.extend_from_slice(frame_bytes);
         self.render_blocks(frame_bytes)
     }
+
+    // Called from the resize watcher so an idle display is redrawn without waiting for a frame
+    pub fn handle_resize(&mut self) -> Result<()> {
+        if self.fixed_size.is_some() {
+            return Ok(());
+        }
+        let (new_w, new_h) = term_size();
+        if new_w == self.term_w && new_h == self.term_h {
+            return Ok(());
+        }
+        self.term_w = new_w;
+        self.term_h = new_h;
+        self.calc_layout();
+        self.redraw = true;
+        
+        if self.placeholder.is_some() {
+            return self.render_placeholder();
+        }
+        self.render_last_frame()
+    }
     
     fn calc_layout(&mut self) {
-        let max_w = self.term_w.saturating_sub(2);
-        let max_h = self.term_h.saturating_sub(3);
-        
-        let scale_x = (self.cam_w as f32 / max_w as f32).ceil() as u32;
-        let scale_y = (self.cam_h as f32 / (max_h * 2) as f32).ceil() as u32;
-        self.scale = scale_x.max(scale_y).max(2);
+        let (max_w, max_h) = match self.fixed_size {
+            Some((w, h)) => {
+                self.too_small = false;
+                (w.max(1), h.max(1))
+            }
+            None => {
+                self.too_small = self.term_w < MIN_TERM_W || self.term_h < MIN_TERM_H;
+                (self.term_w.saturating_sub(2).max(1), self.term_h.saturating_sub(3).max(1))
+            }
+        };
+        let (cam_w, cam_h) = (self.cam_w as f32, self.cam_h as f32);
         
-        self.disp_w = (self.cam_w / self.scale).max(1) as usize;
-        self.disp_h = (self.cam_h / (self.scale * 2)).max(1) as usize;
+        self.crop_x = 0;
+        self.crop_y = 0;
+        match self.fill {
+            None => {
+                let scale_x = (cam_w / max_w as f32).ceil() as u32;
+                let scale_y = (cam_h / (max_h * 2) as f32).ceil() as u32;
+                let scale = scale_x.max(scale_y).max(2);
+                
+                self.disp_w = (self.cam_w / scale).max(1) as usize;
+                self.disp_h = (self.cam_h / (scale * 2)).max(1) as usize;
+                self.step_x = scale as f32;
+                self.step_y = scale as f32;
+            }
+            Some(FillMode::Stretch) => {
+                self.disp_w = max_w;
+                self.disp_h = max_h;
+                self.step_x = cam_w / max_w as f32;
+                self.step_y = cam_h / (max_h * 2) as f32;
+            }
+            Some(FillMode::Crop) => {
+                // The smaller ratio covers the terminal on both axes; the overflow is centered and cut
+                let scale = (cam_w / max_w as f32).min(cam_h / (max_h * 2) as f32);
+                self.disp_w = max_w;
+                self.disp_h = max_h;
+                self.step_x = scale;
+                self.step_y = scale;
+                self.crop_x = ((cam_w - max_w as f32 * scale) / 2.0).max(0.0) as usize;
+                self.crop_y = ((cam_h - (max_h * 2) as f32 * scale) / 2.0).max(0.0) as usize;
+            }
+        }
         
         self.h_pad = (self.term_w.saturating_sub(self.disp_w)) / 2;
         self.v_pad = (self.term_h.saturating_sub(self.disp_h).saturating_sub(2)) / 2;
     }
 
     fn render_blocks(&mut self, frame_bytes: &[u8]) -> Result<()> {
+        self.compose_frame(frame_bytes);
+        self.writer.write_all(self.buf.as_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    // Builds the escape sequences for one frame without touching the terminal (used by the benches)
+    pub fn compose_frame(&mut self, frame_bytes: &[u8]) -> &str {
         self.buf.clear();
+
+        // Leaving this state goes through handle_resize, which already forces a full redraw
+        if self.too_small {
+            if self.supports_color {
+                self.buf.push_str(if self.redraw { "\x1B[2J\x1B[H" } else { "\x1B[H" });
+            }
+            self.redraw = false;
+            let notice = format!("terminal too small, resize to at least {}x{}", MIN_TERM_W, MIN_TERM_H);
+            self.buf.extend(notice.chars().take(self.term_w.max(1)));
+            self.buf.push('\n');
+            return &self.buf;
+        }
         
         if self.redraw {
             if self.supports_color {
@@ -104,7 +536,7 @@ impl TerminalDisplay {
                 }
                 // Move cursor to top
                 for _ in 0..self.term_h {
-                    self.buf.push_str("\x08");
+                    self.buf.push('\x08');
                 }
             }
             self.redraw = false;
@@ -118,6 +550,12 @@ impl TerminalDisplay {
         
         let mut last_top = (255u8, 255u8, 255u8);
         let mut last_bot = (255u8, 255u8, 255u8);
+        let mut last_256: Option<(u8, u8)> = None;
+        let overlay = self
+            .overlay
+            .as_ref()
+            .filter(|(_, shown_at)| shown_at.elapsed() < OVERLAY_TTL)
+            .map(|(lines, _)| OverlayBox::new(lines, self.disp_w, self.disp_h));
         
         for y in 0..self.disp_h {
             for _ in 0..self.h_pad {
@@ -125,38 +563,81 @@ impl TerminalDisplay {
             }
             
             for x in 0..self.disp_w {
-                let src_x = ((x as u32 * self.scale) as usize).min(self.cam_w as usize - 1);
-                let src_y_top = ((y as u32 * self.scale * 2) as usize).min(self.cam_h as usize - 1);
-                let src_y_bot = (((y as u32 * self.scale * 2) + self.scale) as usize).min(self.cam_h as usize - 1);
+                let src_x = (self.crop_x + (x as f32 * self.step_x) as usize).min(self.cam_w as usize - 1);
+                let src_y_top = (self.crop_y + ((y * 2) as f32 * self.step_y) as usize).min(self.cam_h as usize - 1);
+                let src_y_bot = (self.crop_y + ((y * 2 + 1) as f32 * self.step_y) as usize).min(self.cam_h as usize - 1);
                 
                 let top_idx = (src_y_top * self.cam_w as usize + src_x) * 3; // RGB bytes
                 let bot_idx = (src_y_bot * self.cam_w as usize + src_x) * 3; // RGB bytes
                 
                 if top_idx + 2 < frame_bytes.len() && bot_idx + 2 < frame_bytes.len() {
-                    let r1 = frame_bytes[top_idx];
-                    let g1 = frame_bytes[top_idx + 1];
-                    let b1 = frame_bytes[top_idx + 2];
+                    let lut = &self.gamma_lut;
+                    let r1 = lut[frame_bytes[top_idx] as usize];
+                    let g1 = lut[frame_bytes[top_idx + 1] as usize];
+                    let b1 = lut[frame_bytes[top_idx + 2] as usize];
                     
-                    let r2 = frame_bytes[bot_idx];
-                    let g2 = frame_bytes[bot_idx + 1];
-                    let b2 = frame_bytes[bot_idx + 2];
+                    let r2 = lut[frame_bytes[bot_idx] as usize];
+                    let g2 = lut[frame_bytes[bot_idx + 1] as usize];
+                    let b2 = lut[frame_bytes[bot_idx + 2] as usize];
+
+                    // The video stays visible behind the overlay, just dark enough for white text
+                    let cell = overlay.as_ref().and_then(|overlay| overlay.cell(x, y));
+                    let [r1, g1, b1, r2, g2, b2] = match cell {
+                        Some(_) => [r1, g1, b1, r2, g2, b2].map(|c| c / 3),
+                        None => [r1, g1, b1, r2, g2, b2],
+                    };
+                    if let Some(text) = cell.filter(|c| *c != ' ') {
+                        let bg = (
+                            ((r1 as u16 + r2 as u16) / 2) as u8,
+                            ((g1 as u16 + g2 as u16) / 2) as u8,
+                            ((b1 as u16 + b2 as u16) / 2) as u8,
+                        );
+                        if self.supports_color && self.truecolor {
+                            if last_top != (255, 255, 255) || last_bot != bg {
+                                let _ = write!(self.buf, "\x1B[38;2;255;255;255m\x1B[48;2;{};{};{}m", bg.0, bg.1, bg.2);
+                                last_top = (255, 255, 255);
+                                last_bot = bg;
+                            }
+                        } else if self.supports_color {
+                            let colors = (231, ansi256(bg.0, bg.1, bg.2));
+                            if last_256 != Some(colors) {
+                                let _ = write!(self.buf, "\x1B[38;5;{}m\x1B[48;5;{}m", colors.0, colors.1);
+                                last_256 = Some(colors);
+                            }
+                        }
+                        self.buf.push(text);
+                        continue;
+                    }
                     
-                    if self.supports_color {
+                    if self.supports_color && self.truecolor {
+                        let mask = self.color_mask;
+                        let [r1, g1, b1, r2, g2, b2] = [r1, g1, b1, r2, g2, b2].map(|c| c & mask);
                         if (r1, g1, b1) != last_top || (r2, g2, b2) != last_bot {
-                            self.buf.push_str(&format!("\x1B[38;2;{};{};{}m\x1B[48;2;{};{};{}m", r1, g1, b1, r2, g2, b2));
+                            let _ = write!(self.buf, "\x1B[38;2;{};{};{}m\x1B[48;2;{};{};{}m", r1, g1, b1, r2, g2, b2);
                             last_top = (r1, g1, b1);
                             last_bot = (r2, g2, b2);
                         }
                         self.buf.push('▀');
+                    } else if self.supports_color {
+                        let colors = (ansi256(r1, g1, b1), ansi256(r2, g2, b2));
+                        if last_256 != Some(colors) {
+                            let _ = write!(self.buf, "\x1B[38;5;{}m\x1B[48;5;{}m", colors.0, colors.1);
+                            last_256 = Some(colors);
+                        }
+                        self.buf.push('▀');
+                    } else if cell.is_some() {
+                        self.buf.push(' ');
                     } else {
-                        let brightness = ((r1 as u16 + g1 as u16 + b1 as u16) / 3) as u8;
-                        let char = match brightness {
-                            0..=51 => ' ',
-                            52..=102 => '.',
-                            103..=153 => ':',
-                            154..=204 => '#',
-                            _ => '@',
-                        };
+                        let mut brightness = ((r1 as u16 + g1 as u16 + b1 as u16) / 3) as u8;
+                        let levels = self.ramp.len();
+                        if self.dither {
+                            // Ordered dithering: nudge by up to half a ramp step using the Bayer matrix
+                            let threshold = BAYER_4X4[y % 4][x % 4] as i16;
+                            let ramp_step = (256 / levels) as i16;
+                            let offset = (threshold * 2 - 15) * ramp_step / 32;
+                            brightness = (brightness as i16 + offset).clamp(0, 255) as u8;
+                        }
+                        let char = self.ramp[ramp_index(brightness, levels)];
                         self.buf.push(char);
                     }
                 } else {
@@ -168,28 +649,211 @@ impl TerminalDisplay {
                 self.buf.push_str("\x1B[0m\n");
                 last_top = (255, 255, 255);
                 last_bot = (255, 255, 255);
+                last_256 = None;
             } else {
                 self.buf.push('\n');
             }
         }
         
-        self.writer.write_all(self.buf.as_bytes())?;
-        self.writer.flush()?;
-        Ok(())
+        // Caption row sits below the image; padded to full width so an expired caption is erased
+        let caption = match &self.caption {
+            Some((text, shown_at)) if shown_at.elapsed() < CAPTION_TTL => text.clone(),
+            _ => String::new(),
+        };
+        self.push_text_row(&caption);
+        let status = self.status_line();
+        self.push_text_row(&status);
+        &self.buf
+    }
+
+    fn status_line(&self) -> String {
+        [&self.occupancy, &self.presence, &self.status]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join("  ")
     }
+
+    fn push_text_row(&mut self, text: &str) {
+        for _ in 0..self.h_pad {
+            self.buf.push(' ');
+        }
+        let mut written = 0;
+        for c in text.chars().take(self.disp_w) {
+            self.buf.push(c);
+            written += 1;
+        }
+        for _ in written..self.disp_w {
+            self.buf.push(' ');
+        }
+        self.buf.push('\n');
+    }
+}
+
+pub fn supports_truecolor() -> bool {
+    // Windows consoles with virtual terminal processing all handle 24-bit color
+    if cfg!(windows) {
+        return true;
+    }
+    matches!(std::env::var("COLORTERM").as_deref(), Ok("truecolor") | Ok("24bit"))
+}
+
+// Nearest xterm-256 entry, picking between the 6x6x6 color cube and the 24-step gray ramp
+fn ansi256(r: u8, g: u8, b: u8) -> u8 {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let to_cube = |v: u8| -> usize {
+        match v {
+            0..=47 => 0,
+            48..=114 => 1,
+            _ => ((v - 35) / 40) as usize,
+        }
+    };
+    let (cr, cg, cb) = (to_cube(r), to_cube(g), to_cube(b));
+    let cube = (LEVELS[cr], LEVELS[cg], LEVELS[cb]);
+    
+    let avg = ((r as u16 + g as u16 + b as u16) / 3) as u8;
+    let gray_step = if avg > 238 { 23 } else { avg.saturating_sub(3) / 10 };
+    let gray = 8 + gray_step * 10;
+    
+    let dist = |c: (u8, u8, u8)| -> u32 {
+        let dr = r as i32 - c.0 as i32;
+        let dg = g as i32 - c.1 as i32;
+        let db = b as i32 - c.2 as i32;
+        (dr * dr + dg * dg + db * db) as u32
+    };
+    if dist((gray, gray, gray)) < dist(cube) {
+        232 + gray_step
+    } else {
+        (16 + 36 * cr + 6 * cg + cb) as u8
+    }
+}
+
+// Values above 1.0 brighten the image; 1.0 is an exact identity mapping.
+fn gamma_lut(gamma: f32) -> [u8; 256] {
+    let mut lut = [0u8; 256];
+    for (i, v) in lut.iter_mut().enumerate() {
+        *v = if gamma == 1.0 {
+            i as u8
+        } else {
+            ((i as f32 / 255.0).powf(1.0 / gamma) * 255.0).round().clamp(0.0, 255.0) as u8
+        };
+    }
+    lut
 }
 
-fn term_size() -> (usize, usize) {
+pub fn watch_resize() -> tokio::sync::mpsc::UnboundedReceiver<()> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            if let Ok(mut winch) = signal(SignalKind::window_change()) {
+                while winch.recv().await.is_some() {
+                    if tx.send(()).is_err() {
+                        return;
+                    }
+                }
+                return;
+            }
+        }
+        
+        // No SIGWINCH here, fall back to a slow poll
+        let mut last = term_size();
+        let mut interval = tokio::time::interval(Duration::from_millis(500));
+        loop {
+            interval.tick().await;
+            let size = term_size();
+            if size != last {
+                last = size;
+                if tx.send(()).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+pub fn term_size() -> (usize, usize) {
     terminal_size::terminal_size()
         .map(|(terminal_size::Width(w), terminal_size::Height(h))| (w as usize, h as usize))
         .unwrap_or((120, 40))
 }
 
+// Shows the cursor and resets colors if a display left them changed. Safe to call from
+// anywhere, including a panic or right before `process::exit`
+pub fn restore_terminal() {
+    if CURSOR_HIDDEN.swap(false, Ordering::SeqCst) {
+        print!("\x1B[?25h\x1B[0m");
+        let _ = io::stdout().flush();
+    }
+}
+
+// Restores the terminal before the default panic message, so a crash doesn't leave it broken
+pub fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        previous(info);
+    }));
+}
+
 impl Drop for TerminalDisplay {
     fn drop(&mut self) {
         if self.supports_color {
-            print!("\x1B[?25h\x1B[0m");
+            print!("\x1B[0m");
         }
+        restore_terminal();
         let _ = io::stdout().flush();
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gamma_one_is_the_identity() {
+        let lut = gamma_lut(1.0);
+        for (i, &v) in lut.iter().enumerate() {
+            assert_eq!(v as usize, i);
+        }
+    }
+
+    #[test]
+    fn gamma_luts_are_monotonic() {
+        for gamma in [0.3, 0.8, 1.0, 1.8, 2.2, 4.0] {
+            let lut = gamma_lut(gamma);
+            assert!(lut.windows(2).all(|pair| pair[0] <= pair[1]), "gamma {} is not monotonic", gamma);
+            assert_eq!((lut[0], lut[255]), (0, 255));
+        }
+    }
+
+    #[test]
+    fn palettes_map_brightness_monotonically_onto_every_glyph() {
+        for palette in [Palette::Standard, Palette::Dense, Palette::Blocks] {
+            let ramp = palette.ramp();
+            assert!(ramp.len() >= 5, "{palette:?} has too few levels");
+            assert_eq!(ramp[0], ' ', "{palette:?} should start from blank");
+            let distinct: std::collections::HashSet<_> = ramp.iter().collect();
+            assert_eq!(distinct.len(), ramp.len(), "{palette:?} repeats a glyph");
+
+            let indices: Vec<usize> = (0..=255u8).map(|b| ramp_index(b, ramp.len())).collect();
+            assert!(indices.windows(2).all(|pair| pair[0] <= pair[1]), "{palette:?} is not monotonic");
+            assert_eq!(indices[0], 0);
+            assert_eq!(indices[255], ramp.len() - 1);
+            assert!((0..ramp.len()).all(|level| indices.contains(&level)), "{palette:?} skips a glyph");
+        }
+    }
+
+    #[test]
+    fn empty_frames_are_dropped_without_rendering() {
+        let options = DisplayOptions { ascii: true, ..DisplayOptions::default() };
+        let mut display = TerminalDisplay::new(0, 0, &options);
+        display.show_frame(&[]).unwrap();
+        display.show_frame(&[1, 2, 3]).unwrap();
+        assert!(display.last_frame.is_empty());
+        assert!(display.buf.is_empty());
+        assert!(display.empty_frame_logged);
+    }
+}
@@ -0,0 +1,125 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AppEvent {
+    RoomCode { code: String, ticket: String },
+    Connecting { topic: String },
+    Connected,
+    PeerJoined { peer: String },
+    PeerLeft { peer: String },
+    PeerRejected { peer: String },
+    RoomFull,
+    RoomClosed,
+    Kicked,
+    FrameStats {
+        frames_sent: u64,
+        frames_received: u64,
+        bytes_sent: u64,
+        bytes_received: u64,
+        frames_skipped: u64,
+        frames_unchanged: u64,
+        fps: f32,
+    },
+    Error { message: String },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    ts_ms: u128,
+    #[serde(flatten)]
+    event: &'a AppEvent,
+}
+
+// Newline-delimited JSON sink for GUI frontends, independent of the human-readable output
+#[derive(Clone, Default)]
+pub struct EventSink {
+    out: Option<Arc<Mutex<File>>>,
+}
+
+impl EventSink {
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    // Accepts a file path, or `fd:N` for a descriptor the parent process left open for us
+    pub fn open(target: &str) -> Result<Self> {
+        if let Some(fd) = target.strip_prefix("fd:") {
+            return Self::from_fd(fd);
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(target)
+            .with_context(|| format!("failed to open events output {}", target))?;
+        Ok(Self { out: Some(Arc::new(Mutex::new(file))) })
+    }
+
+    #[cfg(unix)]
+    fn from_fd(fd: &str) -> Result<Self> {
+        use std::os::unix::io::{FromRawFd, RawFd};
+
+        let fd: RawFd = fd
+            .parse()
+            .map_err(|_| anyhow!("--events fd:{} is not a descriptor number", fd))?;
+        // The sink closes its descriptor when dropped, which must never be stdin, stdout or stderr
+        if fd < 3 {
+            return Err(anyhow!("--events fd:{} must be 3 or above, 0-2 are the standard streams", fd));
+        }
+        // SAFETY: F_GETFD only reads the descriptor flags, on any number
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            return Err(anyhow!("--events fd:{} is not an open descriptor", fd));
+        }
+        // SAFETY: the descriptor is open and not a standard stream, and `fd:N` is the parent
+        // handing it to this process to write to and close; nothing else here uses it
+        let file = unsafe { File::from_raw_fd(fd) };
+        Ok(Self { out: Some(Arc::new(Mutex::new(file))) })
+    }
+
+    #[cfg(not(unix))]
+    fn from_fd(fd: &str) -> Result<Self> {
+        Err(anyhow!("--events fd:{} needs unix, pass a file path instead", fd))
+    }
+
+    pub fn emit(&self, event: AppEvent) {
+        let Some(out) = &self.out else {
+            return;
+        };
+        let ts_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        if let Ok(mut line) = serde_json::to_vec(&Envelope { ts_ms, event: &event }) {
+            line.push(b'\n');
+            if let Ok(mut out) = out.lock() {
+                let _ = out.write_all(&line);
+                let _ = out.flush();
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard_streams_are_refused() {
+        for fd in ["fd:0", "fd:1", "fd:2", "fd:-1"] {
+            assert!(EventSink::open(fd).is_err(), "{} was accepted", fd);
+        }
+    }
+
+    #[test]
+    fn closed_or_malformed_descriptors_are_refused() {
+        assert!(EventSink::open("fd:987654").is_err());
+        assert!(EventSink::open("fd:three").is_err());
+    }
+}
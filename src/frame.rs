@@ -0,0 +1,216 @@
+// Fits the camera frame inside the send limit, keeping its aspect ratio and never upscaling
+pub fn send_dimensions(native_w: u32, native_h: u32, max_w: u32, max_h: u32) -> (u32, u32) {
+    let scale = (max_w as f32 / native_w as f32)
+        .min(max_h as f32 / native_h as f32)
+        .min(1.0);
+    let w = ((native_w as f32 * scale).round() as u32).max(1);
+    let h = ((native_h as f32 * scale).round() as u32).max(1);
+    (w, h)
+}
+
+pub fn frames_differ(frame1: &[u8], frame2: &[u8], threshold_percent: u8) -> bool {
+    if frame1.len() != frame2.len() || frame1.is_empty() {
+        return true;
+    }
+    
+    let total_pixels = frame1.len() / 3;
+    
+    let step = if total_pixels < 1000 { 
+        3 
+    } else if total_pixels < 10000 { 
+        9 
+    } else { 
+        15 
+    };
+    
+    let mut different_pixels = 0;
+    let mut sampled_pixels = 0;
+    
+    let max_allowed_diff = (total_pixels * threshold_percent as usize) / (100 * (step / 3));
+    
+    for i in (0..frame1.len() - 2).step_by(step) {
+        sampled_pixels += 1;
+        
+        let pixel_diff = ((frame1[i] as u16).abs_diff(frame2[i] as u16)) +
+                        ((frame1[i + 1] as u16).abs_diff(frame2[i + 1] as u16)) +
+                        ((frame1[i + 2] as u16).abs_diff(frame2[i + 2] as u16));
+        
+        if pixel_diff > 45 {
+            different_pixels += 1;
+            
+            if different_pixels > max_allowed_diff {
+                return true;
+            }
+        }
+    }
+    
+    let change_percent = (different_pixels * 100).checked_div(sampled_pixels).unwrap_or(100);
+    
+    change_percent > threshold_percent as usize
+}
+
+// Nearest-neighbour resample of an RGB frame to `new_w` x `new_h`
+pub fn reduce_frame_size(frame: &[u8], orig_w: u32, orig_h: u32, new_w: u32, new_h: u32) -> Vec<u8> {
+    // Nothing to sample from, so come out black rather than underflow below
+    if orig_w == 0 || orig_h == 0 {
+        return vec![0; (new_w * new_h * 3) as usize];
+    }
+    let mut reduced = Vec::with_capacity((new_w * new_h * 3) as usize);
+    
+    for y in 0..new_h {
+        for x in 0..new_w {
+            let orig_x = ((x as f32 / new_w as f32) * orig_w as f32) as u32;
+            let orig_y = ((y as f32 / new_h as f32) * orig_h as f32) as u32;
+            
+            let orig_x = orig_x.min(orig_w - 1);
+            let orig_y = orig_y.min(orig_h - 1);
+            
+            let idx = ((orig_y * orig_w + orig_x) * 3) as usize;
+            if idx + 2 < frame.len() {
+                reduced.extend_from_slice(&[frame[idx], frame[idx + 1], frame[idx + 2]]);
+            } else {
+                reduced.extend_from_slice(&[0, 0, 0]);
+            }
+        }
+    }
+    
+    reduced
+}
+
+// Fades from whatever is on screen to each new frame over a few render ticks
+pub struct Crossfade {
+    steps: u32,
+    step: u32,
+    width: u32,
+    height: u32,
+    from: Vec<u8>,
+    to: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl Crossfade {
+    pub fn new(steps: u32) -> Self {
+        Self {
+            steps,
+            step: 0,
+            width: 0,
+            height: 0,
+            from: Vec::new(),
+            to: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    // Returns false when the frame should just be shown as is: fading is off, it's the
+    // first frame, or the size changed
+    pub fn start(&mut self, frame: &[u8], width: u32, height: u32) -> bool {
+        if self.steps < 2 {
+            return false;
+        }
+        let blendable = !self.to.is_empty()
+            && (self.width, self.height) == (width, height)
+            && self.to.len() == frame.len();
+        if blendable {
+            // Start from what is currently visible, which is mid-fade if frames arrive quickly
+            if self.step < self.steps && self.out.len() == frame.len() {
+                std::mem::swap(&mut self.from, &mut self.out);
+            } else {
+                std::mem::swap(&mut self.from, &mut self.to);
+            }
+        }
+        self.to.clear();
+        self.to.extend_from_slice(frame);
+        self.width = width;
+        self.height = height;
+        self.step = if blendable { 0 } else { self.steps };
+        blendable
+    }
+
+    pub fn next_frame(&mut self) -> Option<(&[u8], u32, u32)> {
+        if self.step >= self.steps {
+            return None;
+        }
+        self.step += 1;
+        let weight = (self.step * 256 / self.steps) as u16;
+        self.out.clear();
+        self.out.extend(
+            self.from
+                .iter()
+                .zip(&self.to)
+                .map(|(&a, &b)| ((a as u16 * (256 - weight) + b as u16 * weight) >> 8) as u8),
+        );
+        Some((&self.out, self.width, self.height))
+    }
+}
+
+// Several feeds tiled into one RGB picture, in a grid filled left to right and top to bottom.
+// Each feed is scaled to fit its cell, keeping its aspect ratio, and centered on black
+pub struct Mosaic {
+    cell_w: u32,
+    cell_h: u32,
+    cols: u32,
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl Mosaic {
+    pub fn new(tiles: usize, cell_w: u32, cell_h: u32) -> Self {
+        let tiles = tiles.max(1) as u32;
+        let cols = (1..=tiles).find(|cols| cols * cols >= tiles).unwrap_or(tiles);
+        let rows = tiles.div_ceil(cols);
+        let width = cols * cell_w;
+        let height = rows * cell_h;
+        Self {
+            cell_w,
+            cell_h,
+            cols,
+            data: vec![0; (width * height * 3) as usize],
+            width,
+            height,
+        }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn set_tile(&mut self, index: usize, frame: &[u8], width: u32, height: u32) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        self.clear_tile(index);
+        let scale = (self.cell_w as f32 / width as f32).min(self.cell_h as f32 / height as f32);
+        let fit_w = ((width as f32 * scale) as u32).clamp(1, self.cell_w);
+        let fit_h = ((height as f32 * scale) as u32).clamp(1, self.cell_h);
+        let scaled = reduce_frame_size(frame, width, height, fit_w, fit_h);
+        let (x0, y0) = self.cell_origin(index);
+        let x0 = x0 + (self.cell_w - fit_w) / 2;
+        let y0 = y0 + (self.cell_h - fit_h) / 2;
+        for (row, line) in scaled.chunks_exact((fit_w * 3) as usize).enumerate() {
+            let start = (((y0 + row as u32) * self.width + x0) * 3) as usize;
+            if let Some(target) = self.data.get_mut(start..start + line.len()) {
+                target.copy_from_slice(line);
+            }
+        }
+    }
+
+    pub fn clear_tile(&mut self, index: usize) {
+        let (x0, y0) = self.cell_origin(index);
+        for y in y0..(y0 + self.cell_h).min(self.height) {
+            let start = ((y * self.width + x0) * 3) as usize;
+            if let Some(target) = self.data.get_mut(start..start + (self.cell_w * 3) as usize) {
+                target.fill(0);
+            }
+        }
+    }
+
+    fn cell_origin(&self, index: usize) -> (u32, u32) {
+        let index = index as u32;
+        ((index % self.cols) * self.cell_w, (index / self.cols) * self.cell_h)
+    }
+}
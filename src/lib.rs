@@ -0,0 +1,5 @@
+pub mod codec;
+pub mod display;
+pub mod events;
+pub mod frame;
+pub mod room;
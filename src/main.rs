@@ -1,117 +1,460 @@
-use std::{collections::HashMap, fmt, str::FromStr, fs};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use futures_lite::StreamExt;
-use iroh::{Endpoint, NodeAddr, NodeId, Watcher};
+use anyhow::{Context, Result};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use iroh::{
+    endpoint::ConnectionType, Endpoint, NodeAddr, NodeId, RelayMap, RelayMode, RelayUrl, SecretKey, Watcher,
+};
 use iroh_gossip::{
-    api::{Event, GossipReceiver, GossipSender},
+    api::GossipSender,
     net::{Gossip, GOSSIP_ALPN},
     proto::TopicId,
 };
 use serde::{Deserialize, Serialize};
 
-#[cfg(target_os = "windows")]
-use windows::Win32::System::Com::{CoInitializeEx, CoUninitialize, COINIT_APARTMENTTHREADED, COINIT_MULTITHREADED};
-
 #[cfg(windows)]
 use colored::control;
 
 mod camera;
-mod display;
+mod chat;
+mod config;
+mod record;
+mod screen;
+mod stats;
+mod still;
+mod tune;
+mod watch;
 
-use camera::CameraCapture;
-use display::TerminalDisplay;
+use camera::{CameraCapture, CaptureGate, CaptureWorker, FrameSource, OpenSource};
+use screen::ScreenCapture;
+use still::StillImage;
+use p2p_video_chat::codec::{Capabilities, Frame, ENCODING_RAW};
+use p2p_video_chat::display::{self, DisplayOptions, FillMode, Palette, Placeholder, TerminalDisplay};
+use p2p_video_chat::events::{AppEvent, EventSink};
+use p2p_video_chat::frame::Crossfade;
+use p2p_video_chat::room::{
+    send_room_info, short_id, subscribe_loop, Activity, Failure, Message, MessageBody, PresenceState, Rejoin,
+    RoomSettings, VideoChannels, ID_LEN, MAX_MESSAGE_SIZE, ROOM_CAPACITY,
+};
+use stats::Stats;
 
 #[derive(Parser)]
 #[command(name = "p2p-videochat", about = "peer-to-peer video chat app using Iroh")]
 struct Cli {
+    /// Gamma correction for received video (values above 1.0 brighten)
+    #[arg(long, global = true, default_value_t = 1.0)]
+    gamma: f32,
+    /// Render received video as monochrome ASCII instead of color blocks
+    #[arg(long, global = true)]
+    ascii: bool,
+    /// Apply ordered dithering to the ASCII renderer
+    #[arg(long, global = true)]
+    dither: bool,
+    /// Bits per color channel in truecolor mode (1-8); lower values write far less to the terminal, useful over slow SSH
+    #[arg(long, global = true, default_value_t = 8, value_parser = clap::value_parser!(u8).range(1..=8))]
+    color_bits: u8,
+    /// Time a few test frames at startup and pick the richest colors and largest video size this terminal keeps up with
+    #[arg(long, global = true)]
+    auto_tune: bool,
+    /// Character ramp used when rendering without color
+    #[arg(long, global = true, value_enum, default_value_t = Palette::Standard)]
+    palette: Palette,
+    /// Fill the terminal instead of fitting the video with padding (crop by default, or stretch)
+    #[arg(long, global = true, value_enum, num_args = 0..=1, default_missing_value = "crop")]
+    fill: Option<FillMode>,
+    /// Join the room with the text chat interface instead of video
+    #[arg(long, global = true)]
+    chat: bool,
+    /// Write newline-delimited JSON events to a file path, or on unix to an inherited file
+    /// descriptor given as `fd:N` (3 or above)
+    #[arg(long, global = true, value_name = "PATH_OR_FD")]
+    events: Option<String>,
+    /// Show frame statistics below the video
+    #[arg(long, global = true)]
+    stats: bool,
+    /// Seconds between keepalive messages (0 disables them)
+    #[arg(long, global = true, default_value_t = 30)]
+    keepalive_secs: u64,
+    /// Exit if no peer shows up, or a connected peer goes silent, for this many seconds (0 disables it)
+    #[arg(long, global = true, default_value_t = 0, value_name = "SECS")]
+    idle_timeout: u64,
+    /// Crossfade between received frames over a few render ticks to smooth low framerates
+    #[arg(long, global = true)]
+    interpolate: bool,
+    /// Milliseconds received frames wait before display to smooth out bursty delivery (0 shows them immediately)
+    #[arg(long, global = true, default_value_t = 100)]
+    jitter_ms: u64,
+    /// Share the primary screen instead of the camera
+    #[arg(long, global = true)]
+    screen: bool,
+    /// Send a PNG or JPEG picture instead of the camera
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "screen")]
+    image: Option<PathBuf>,
+    /// Exit if the camera can't be opened instead of falling back to text chat, and never send placeholder frames
+    #[arg(long, global = true)]
+    require_camera: bool,
+    /// Send small thumbnails while the picture moves and full resolution only when it settles,
+    /// changes a lot or a peer asks for it; saves bandwidth on slides and screen shares
+    #[arg(long, global = true)]
+    thumbnails: bool,
+    /// Copy the full room ticket to the clipboard once the room is ready
+    #[arg(long, global = true)]
+    copy: bool,
+    /// On `open`, print the ticket as JSON (topic, nodes, short code) instead of the room code
+    /// line, for other tools to parse
+    #[arg(long, global = true)]
+    print_ticket_json: bool,
+    /// Keep the terminal cursor visible while video plays (for debugging)
+    #[arg(long, global = true)]
+    show_cursor: bool,
+    /// No colors anywhere, video included (same as setting NO_COLOR)
+    #[arg(long, global = true, conflicts_with = "force_color")]
+    no_color: bool,
+    /// Colors even when output isn't a terminal, e.g. when piping into `less -R`
+    #[arg(long, global = true)]
+    force_color: bool,
+    /// Seconds to wait for this machine's network addresses before making the room code
+    /// without them
+    #[arg(long, global = true, default_value_t = 10, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    init_timeout: u64,
+    /// Name shown for your own messages in chat mode, and to joiners of rooms you open
+    #[arg(long, global = true)]
+    nick: Option<String>,
+    /// Append a plain-text, timestamped chat transcript to this file
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// Number of chat lines kept in memory (older lines are dropped)
+    #[arg(long, global = true, default_value_t = 500)]
+    scrollback: usize,
+    /// Secret key file to reuse the same node id across runs (created if missing)
+    #[arg(long, global = true, value_name = "PATH")]
+    identity: Option<PathBuf>,
+    /// Use this relay server instead of the default n0 relays
+    #[arg(long, global = true, value_name = "URL", conflicts_with = "direct_only")]
+    relay_url: Option<RelayUrl>,
+    /// Don't publish or look up node addresses through n0 discovery
+    #[arg(long, global = true)]
+    no_discovery: bool,
+    /// Disable relays, so connections only succeed over direct paths
+    #[arg(long, global = true)]
+    direct_only: bool,
+    /// Offline LAN mode: skip n0 discovery and relays, find peers on this network over mDNS
+    /// (pass --relay-url to use a relay running on the LAN)
+    #[arg(long, global = true)]
+    lan: bool,
+    /// How many characters of a peer's node id to show in messages (defaults to iroh's short form)
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u8).range(4..=64))]
+    id_len: Option<u8>,
+    /// Local address to bind, to pick the network interface used for direct connections
+    /// (an IP, or IP:PORT; the port defaults to any free one)
+    #[arg(long, global = true, value_name = "ADDR", value_parser = parse_bind_addr)]
+    bind: Option<std::net::SocketAddr>,
+    /// Which address families to advertise in tickets and dial when joining
+    #[arg(long, global = true, value_enum, default_value_t = AddrFamily::Both)]
+    prefer: AddrFamily,
+    /// Maximum width of the video sent to peers
+    #[arg(long, global = true, default_value_t = 640)]
+    width: u32,
+    /// Maximum height of the video sent to peers
+    #[arg(long, global = true, default_value_t = 480)]
+    height: u32,
+    /// Percent of sampled pixels that must change before a new frame is sent (0-100; lower is
+    /// smoother, higher saves bandwidth on static scenes)
+    #[arg(long, global = true, default_value_t = 1, value_name = "PERCENT")]
+    motion_threshold: u8,
+    /// Milliseconds between captured frames (33 is about 30 fps; raise it to save bandwidth)
+    #[arg(long, global = true, default_value_t = 33, value_parser = clap::value_parser!(u64).range(1..))]
+    interval_ms: u64,
+    /// Lock the video to WxH terminal cells and ignore resizes, for a stable recording region
+    #[arg(long, global = true, value_name = "WxH", value_parser = parse_fixed_size)]
+    fixed_size: Option<(usize, usize)>,
+    /// Save the peer's video to this directory as it is shown, to watch later with `replay`
+    #[arg(long, global = true, value_name = "DIR")]
+    record: Option<PathBuf>,
     #[command(subcommand)]
     commands: Commands,
 }
 
-#[derive(Subcommand)]
-enum Commands {
-    Open,
-    Join { ticket: String },
+fn parse_bind_addr(value: &str) -> Result<std::net::SocketAddr, String> {
+    if let Ok(addr) = value.parse::<std::net::SocketAddr>() {
+        return Ok(addr);
+    }
+    value
+        .parse::<std::net::IpAddr>()
+        .map(|ip| std::net::SocketAddr::new(ip, 0))
+        .map_err(|_| format!("expected an IP address or IP:PORT, got `{}`", value))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Message {
-    body: MessageBody,
-    nonce: [u8; 16],
+fn parse_fixed_size(value: &str) -> Result<(usize, usize), String> {
+    let (w, h) = value
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected WxH, got `{}`", value))?;
+    let w: usize = w.trim().parse().map_err(|_| format!("invalid width `{}`", w))?;
+    let h: usize = h.trim().parse().map_err(|_| format!("invalid height `{}`", h))?;
+    if w == 0 || h == 0 {
+        return Err("width and height must be greater than 0".to_string());
+    }
+    Ok((w, h))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum MessageBody {
-    AboutMe { from: NodeId },
-    VideoFrame { 
-        from: NodeId, 
-        frame_data: Vec<u8>,
-        width: u32,
-        height: u32,
-    },
-    RoomFull { from: NodeId, target: NodeId },
-    KeepAlive { from: NodeId },
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum AddrFamily {
+    /// Only IPv4 addresses
+    Ipv4,
+    /// Only IPv6 addresses
+    Ipv6,
+    /// Both families
+    Both,
 }
 
-impl Message {
-    fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        serde_json::from_slice(bytes).map_err(Into::into)
+impl AddrFamily {
+    fn filter<'a>(self, addrs: impl IntoIterator<Item = &'a std::net::SocketAddr>) -> Vec<std::net::SocketAddr> {
+        addrs
+            .into_iter()
+            .filter(|addr| match self {
+                AddrFamily::Ipv4 => addr.is_ipv4(),
+                AddrFamily::Ipv6 => addr.is_ipv6(),
+                AddrFamily::Both => true,
+            })
+            .copied()
+            .collect()
     }
+}
 
-    fn new(body: MessageBody) -> Self {
-        Self {
-            body,
-            nonce: rand::random(),
-        }
-    }
+#[derive(Subcommand)]
+enum Commands {
+    Open {
+        /// Require peers to know this password before they can join. This only keeps out
+        /// people who have the ticket but not the password: the proof joiners send can be
+        /// replayed by anyone who sees it on the wire, so it doesn't stop eavesdroppers
+        #[arg(long)]
+        password: Option<String>,
+        /// Human-readable room title shown to everyone who joins
+        #[arg(long)]
+        title: Option<String>,
+        /// Fixed room topic: 64 hex characters, or any name (hashed), so the same name always
+        /// opens the same room. Anyone who knows the name can join
+        #[arg(long, value_name = "HEX_OR_NAME")]
+        topic: Option<String>,
+    },
+    /// Join a room by code or full ticket (`-` reads it from stdin). Several codes watch
+    /// those rooms side by side, receive-only
+    Join {
+        #[arg(required = true)]
+        tickets: Vec<String>,
+        /// Password of the room, if the host set one
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Check that the host of a room is reachable, then exit
+    Ping { ticket: String },
+    /// Print and save a room code without opening the room (pair with --identity and
+    /// `--topic` so a later `open` with the same flags serves it)
+    Ticket {
+        /// Fixed room topic, same format as `open --topic`
+        #[arg(long, value_name = "HEX_OR_NAME")]
+        topic: Option<String>,
+    },
+    /// Grab one frame from the camera, save it as a PNG and exit
+    TestCamera {
+        /// Where to write the PNG
+        #[arg(default_value = "camera-test.png")]
+        out: PathBuf,
+    },
+    /// Play back a session saved with --record, with no camera or network
+    Replay {
+        /// Directory written by --record
+        path: PathBuf,
+        /// Playback speed (2 plays twice as fast)
+        #[arg(long, default_value_t = 1.0)]
+        speed: f32,
+    },
+    /// Print a shell completion script to stdout
+    #[command(hide = true)]
+    Completions { shell: clap_complete::Shell },
+}
 
-    fn to_vec(&self) -> Vec<u8> {
-        serde_json::to_vec(self).expect("Serialization should never fail")
+// How long the local picture has to stay still before peers are told we're away
+const AWAY_AFTER: std::time::Duration = std::time::Duration::from_secs(60);
+
+// With --thumbnails, what goes out between full frames
+const THUMBNAIL_SIZE: (u32, u32) = (160, 120);
+// Percent of the picture that has to change before a new full frame is sent unasked
+const FULL_FRAME_CHANGE: u8 = 25;
+// Receivers ask for a full frame at most this often
+const FULL_FRAME_REQUEST_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+// Captures a thumbnail has to stay unchanged before that picture goes out at full resolution
+const SETTLE_FRAMES: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CompactNodeInfo {
+    node_id: NodeId,
+    // Lets peers behind NAT be reached from the ticket alone; missing in older saved tickets
+    #[serde(default)]
+    relay_url: Option<RelayUrl>,
+    direct_addresses: Vec<std::net::SocketAddr>,
+}
+
+impl CompactNodeInfo {
+    fn to_node_addr(&self, prefer: AddrFamily) -> NodeAddr {
+        NodeAddr::from_parts(self.node_id, self.relay_url.clone(), prefer.filter(&self.direct_addresses))
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct CompactNodeInfo {
+// Ticket layout before relay urls were included (unversioned and v1 tickets)
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct LegacyTicket {
+    topic: TopicId,
+    nodes: Vec<LegacyNodeInfo>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct LegacyNodeInfo {
     node_id: NodeId,
     direct_addresses: Vec<std::net::SocketAddr>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl From<LegacyTicket> for Ticket {
+    fn from(legacy: LegacyTicket) -> Self {
+        Ticket {
+            topic: legacy.topic,
+            nodes: legacy
+                .nodes
+                .into_iter()
+                .map(|node| CompactNodeInfo {
+                    node_id: node.node_id,
+                    relay_url: None,
+                    direct_addresses: node.direct_addresses,
+                })
+                .collect(),
+            host_name: None,
+        }
+    }
+}
+
+// v2 tickets: relay urls but no host name
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct TicketV2 {
+    topic: TopicId,
+    nodes: Vec<CompactNodeInfo>,
+}
+
+impl From<TicketV2> for Ticket {
+    fn from(v2: TicketV2) -> Self {
+        Ticket {
+            topic: v2.topic,
+            nodes: v2.nodes,
+            host_name: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct Ticket {
     topic: TopicId,
     nodes: Vec<CompactNodeInfo>,
+    // The creator's --nick, so joiners know whose room it is before connecting
+    #[serde(default)]
+    host_name: Option<String>,
+}
+
+// What --print-ticket-json writes: the ticket's own fields plus the short code
+#[derive(Serialize)]
+struct TicketJson<'a> {
+    #[serde(flatten)]
+    ticket: &'a Ticket,
+    short_code: &'a str,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct TicketRegistry {
     tickets: HashMap<String, Ticket>,
+    // The file it was loaded from and saves back to
+    #[serde(skip)]
+    path: PathBuf,
 }
 
 impl TicketRegistry {
-    fn load_or_create() -> Self {
-        let path = dirs::home_dir()
+    fn default_path() -> PathBuf {
+        dirs::home_dir()
             .unwrap_or_else(|| std::env::current_dir().unwrap())
-            .join(".p2p-video-chat-tickets.json");
-        
+            .join(".p2p-video-chat-tickets.json")
+    }
+
+    fn load_or_create() -> Self {
+        Self::load_from(Self::default_path())
+    }
+
+    fn load_from(path: PathBuf) -> Self {
         if let Ok(content) = fs::read_to_string(&path) {
-            if let Ok(registry) = serde_json::from_str(&content) {
-                return registry;
+            match serde_json::from_str::<TicketRegistry>(&content) {
+                Ok(registry) => return Self { path, ..registry },
+                Err(e) => {
+                    // Keep the unreadable file around, the next save would otherwise clobber every saved code
+                    let backup = path.with_extension("json.bak");
+                    match fs::rename(&path, &backup) {
+                        Ok(()) => eprintln!(
+                            "> warning: saved rooms file is corrupt ({}), moved it to {} and starting fresh",
+                            e,
+                            backup.display()
+                        ),
+                        Err(rename_err) => eprintln!(
+                            "> warning: saved rooms file is corrupt ({}) and could not be backed up: {}",
+                            e, rename_err
+                        ),
+                    }
+                }
             }
         }
         
-        Self { tickets: HashMap::new() }
+        Self { tickets: HashMap::new(), path }
     }
     
+    // Written to a temp file and renamed over the old one so a crash never leaves half a file behind
     fn save(&self) -> Result<()> {
-        let path = dirs::home_dir()
-            .unwrap_or_else(|| std::env::current_dir().unwrap())
-            .join(".p2p-video-chat-tickets.json");
-        
-        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, &self.path)?;
         Ok(())
     }
+
+    // Held while reading, updating and saving so two running instances don't drop each other's codes
+    fn lock(&self) -> Result<fs::File> {
+        use fs2::FileExt;
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.with_extension("json.lock"))
+            .context("failed to open the saved rooms lock file")?;
+        file.lock_exclusive().context("failed to lock the saved rooms file")?;
+        Ok(file)
+    }
+
+    fn merge_from_disk(&mut self) {
+        let Ok(content) = fs::read_to_string(&self.path) else {
+            return;
+        };
+        if let Ok(disk) = serde_json::from_str::<TicketRegistry>(&content) {
+            for (code, ticket) in disk.tickets {
+                self.tickets.entry(code).or_insert(ticket);
+            }
+        }
+    }
     
     fn generate_short_code(&self) -> String {
         let chars = b"0123456789abcdefghijklmnopqrstuvwxyz";
@@ -127,7 +470,20 @@ impl TicketRegistry {
     }
     
     fn register_ticket(&mut self, ticket: Ticket) -> Result<String> {
-        let code = self.generate_short_code();
+        let _lock = self.lock()?;
+        self.merge_from_disk();
+        // Same host and topic as a saved entry (a persisted --identity reopening its room) keeps
+        // that code, with the entry refreshed since the addresses may have changed
+        let host = ticket.nodes.first().map(|node| node.node_id);
+        let existing = self
+            .tickets
+            .iter()
+            .filter(|(_, saved)| {
+                saved.topic == ticket.topic && saved.nodes.first().map(|node| node.node_id) == host
+            })
+            .map(|(code, _)| code.clone())
+            .min();
+        let code = existing.unwrap_or_else(|| self.generate_short_code());
         self.tickets.insert(code.clone(), ticket);
         self.save()?;
         Ok(code)
@@ -138,13 +494,70 @@ impl TicketRegistry {
     }
 }
 
+const TICKET_MAGIC: &[u8; 4] = b"p2pv";
+// Same header as `TICKET_MAGIC`, but everything after the version byte is deflated
+const TICKET_MAGIC_DEFLATE: &[u8; 4] = b"p2pz";
+const TICKET_VERSION: u8 = 3;
+// Real tickets inflate to a few hundred bytes, anything past this is corrupt or hostile
+const MAX_TICKET_SIZE: usize = 64 * 1024;
+
 impl Ticket {
     fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        postcard::from_bytes(bytes).map_err(Into::into)
+        if let Some(rest) = bytes.strip_prefix(TICKET_MAGIC_DEFLATE) {
+            let (&version, packed) = rest
+                .split_first()
+                .ok_or_else(|| anyhow::anyhow!("ticket is truncated, make sure it was copied completely"))?;
+            let payload = miniz_oxide::inflate::decompress_to_vec_with_limit(packed, MAX_TICKET_SIZE)
+                .map_err(|_| anyhow::anyhow!("ticket is corrupt, make sure it was copied completely"))?;
+            return Self::decode_payload(version, &payload);
+        }
+        let Some(rest) = bytes.strip_prefix(TICKET_MAGIC) else {
+            // Tickets from before versioning have no header, treat them as version 0
+            return postcard::from_bytes::<LegacyTicket>(bytes)
+                .map(Ticket::from)
+                .context("ticket is corrupt or from an incompatible version");
+        };
+        let (&version, payload) = rest
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("ticket is truncated, make sure it was copied completely"))?;
+        Self::decode_payload(version, payload)
+    }
+
+    fn decode_payload(version: u8, payload: &[u8]) -> Result<Self> {
+        match version {
+            TICKET_VERSION => postcard::from_bytes(payload).context("ticket is corrupt"),
+            2 => postcard::from_bytes::<TicketV2>(payload)
+                .map(Ticket::from)
+                .context("ticket is corrupt"),
+            1 => postcard::from_bytes::<LegacyTicket>(payload)
+                .map(Ticket::from)
+                .context("ticket is corrupt"),
+            newer if newer > TICKET_VERSION => Err(anyhow::anyhow!(
+                "this room code was made by a newer version (v{}, this app supports v{}), please update",
+                newer,
+                TICKET_VERSION
+            )),
+            older => Err(anyhow::anyhow!(
+                "this room code was made by an older version (v{}, this app supports v{})",
+                older,
+                TICKET_VERSION
+            )),
+        }
     }
 
     fn to_bytes(&self) -> Vec<u8> {
-        postcard::to_allocvec(self).expect("Serialization should never fail")
+        let mut bytes = TICKET_MAGIC.to_vec();
+        bytes.push(TICKET_VERSION);
+        bytes.extend(postcard::to_allocvec(self).expect("Serialization should never fail"));
+        bytes
+    }
+
+    fn to_compressed_bytes(&self) -> Vec<u8> {
+        let payload = postcard::to_allocvec(self).expect("Serialization should never fail");
+        let mut bytes = TICKET_MAGIC_DEFLATE.to_vec();
+        bytes.push(TICKET_VERSION);
+        bytes.extend(miniz_oxide::deflate::compress_to_vec(&payload, 10));
+        bytes
     }
 
     fn to_short_code(&self) -> Result<String> {
@@ -153,10 +566,20 @@ impl Ticket {
     }
     
     fn from_code_or_full(input: &str) -> Result<Self> {
-        if input.len() <= 8 {
-            if let Some(ticket) = TicketRegistry::load_or_create().get_ticket(input) {
-                return Ok(ticket.clone());
-            }
+        Self::from_code_or_full_in(input, TicketRegistry::load_or_create)
+    }
+
+    // The saved rooms are only loaded when the input is a short code
+    fn from_code_or_full_in(input: &str, registry: impl FnOnce() -> TicketRegistry) -> Result<Self> {
+        let input = input.trim();
+        if looks_like_short_code(input) {
+            return registry()
+                .get_ticket(input)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!(
+                    "no saved room with code '{}'. room codes only work on the computer that created them, ask the host for the full ticket",
+                    input
+                ));
         }
         input.parse()
     }
@@ -164,449 +587,1785 @@ impl Ticket {
 
 impl fmt::Display for Ticket {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", data_encoding::BASE64URL_NOPAD.encode(&self.to_bytes()))
+        // Keys don't compress, so a ticket with only a couple of addresses can come out shorter raw
+        let plain = self.to_bytes();
+        let compressed = self.to_compressed_bytes();
+        let bytes = if compressed.len() < plain.len() { compressed } else { plain };
+        write!(f, "{}", data_encoding::BASE64URL_NOPAD.encode(&bytes))
     }
 }
 
 impl FromStr for Ticket {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let bytes = data_encoding::BASE64URL_NOPAD.decode(s.as_bytes())?;
+        let bytes = data_encoding::BASE64URL_NOPAD.decode(s.as_bytes())
+            .context("ticket contains invalid characters, make sure it was copied completely")?;
         Self::from_bytes(&bytes)
     }
 }
 
-fn frames_differ(frame1: &[u8], frame2: &[u8], threshold_percent: u8) -> bool {
-    if frame1.len() != frame2.len() || frame1.is_empty() {
-        return true;
+fn read_ticket_arg(arg: &str) -> Result<String> {
+    if arg != "-" {
+        return Ok(arg.to_string());
     }
     
-    let total_pixels = frame1.len() / 3;
-    
-    let step = if total_pixels < 1000 { 
-        3 
-    } else if total_pixels < 10000 { 
-        9 
-    } else { 
-        15 
-    };
-    
-    let mut different_pixels = 0;
-    let mut sampled_pixels = 0;
-    
-    let max_allowed_diff = (total_pixels * threshold_percent as usize) / (100 * (step / 3));
-    
-    for i in (0..frame1.len() - 2).step_by(step) {
-        sampled_pixels += 1;
-        
-        let pixel_diff = ((frame1[i] as u16).abs_diff(frame2[i] as u16)) +
-                        ((frame1[i + 1] as u16).abs_diff(frame2[i + 1] as u16)) +
-                        ((frame1[i + 2] as u16).abs_diff(frame2[i + 2] as u16));
-        
-        if pixel_diff > 45 {
-            different_pixels += 1;
-            
-            if different_pixels > max_allowed_diff {
-                return true;
-            }
+    let mut stdin = std::io::stdin();
+    if std::io::IsTerminal::is_terminal(&stdin) {
+        eprintln!("> paste the room code or ticket, then press Ctrl-D");
+    }
+    let mut input = String::new();
+    std::io::Read::read_to_string(&mut stdin, &mut input)?;
+    // Pasted tickets may be wrapped across lines; base64 never contains whitespace
+    Ok(input.split_whitespace().collect())
+}
+
+fn looks_like_short_code(input: &str) -> bool {
+    !input.is_empty()
+        && input.len() <= 8
+        && input.chars().all(|c| c.is_ascii_digit() || c.is_ascii_lowercase())
+}
+
+// Camera check with no networking involved, so camera problems can be told apart from connection ones
+fn test_camera(out: &Path) -> Result<()> {
+    println!("> initializing camera...");
+    let mut camera = CameraCapture::new()?;
+    let (width, height) = camera.dimensions();
+    println!("> negotiated format: {}", camera.format_description());
+
+    // The first frames are often black while the sensor adjusts exposure
+    for _ in 0..5 {
+        let _ = camera.get_frame();
+    }
+    let (frame, frame_w, frame_h) = camera.get_frame()?;
+    if frame.len() != (frame_w * frame_h * 3) as usize {
+        return Err(anyhow::anyhow!(
+            "camera returned {} bytes for a {}x{} frame",
+            frame.len(),
+            frame_w,
+            frame_h
+        ));
+    }
+    image::save_buffer(out, frame, frame_w, frame_h, image::ColorType::Rgb8)
+        .with_context(|| format!("failed to write {}", out.display()))?;
+    println!("> captured a {}x{} frame (requested {}x{}), saved to {}", frame_w, frame_h, width, height, out.display());
+    Ok(())
+}
+
+// Best effort: headless sessions without a clipboard get the ticket printed instead
+fn copy_to_clipboard(text: &str) -> Option<arboard::Clipboard> {
+    let copied = arboard::Clipboard::new().and_then(|mut clipboard| {
+        clipboard.set_text(text.to_string())?;
+        Ok(clipboard)
+    });
+    match copied {
+        Ok(clipboard) => {
+            println!("> full ticket copied to clipboard");
+            Some(clipboard)
+        }
+        Err(e) => {
+            println!("> couldn't copy to the clipboard ({}), full ticket: {}", e, text);
+            None
         }
     }
-    
-    let change_percent = if sampled_pixels > 0 {
-        (different_pixels * 100) / sampled_pixels
-    } else {
-        100
+}
+
+async fn own_ticket(
+    endpoint: &Endpoint,
+    topic: TopicId,
+    prefer: AddrFamily,
+    host_name: Option<String>,
+    init_timeout: std::time::Duration,
+) -> Ticket {
+    // Never resolves on networks where no address can be discovered, so give up after a while
+    // and hand out the node id alone, which discovery can still look up
+    let me = match tokio::time::timeout(init_timeout, endpoint.node_addr().initialized()).await {
+        Ok(me) => me,
+        Err(_) => {
+            eprintln!("> could not determine network address — check connectivity");
+            eprintln!("> the room code only carries the node id, peers may not be able to reach you");
+            NodeAddr::new(endpoint.node_id())
+        }
     };
-    
-    change_percent > threshold_percent as usize
+    Ticket {
+        topic,
+        nodes: vec![CompactNodeInfo {
+            node_id: me.node_id,
+            relay_url: me.relay_url,
+            direct_addresses: prefer.filter(&me.direct_addresses),
+        }],
+        host_name,
+    }
+}
+
+// A 32-byte hex id is used as-is, anything else is treated as a room name
+fn topic_from_arg(arg: &str) -> TopicId {
+    let arg = arg.trim();
+    if let Ok(bytes) = data_encoding::HEXLOWER_PERMISSIVE.decode(arg.as_bytes()) {
+        if let Ok(bytes) = <[u8; 32]>::try_from(bytes) {
+            return TopicId::from_bytes(bytes);
+        }
+    }
+    TopicId::from_bytes(*blake3::hash(arg.as_bytes()).as_bytes())
+}
+
+fn load_or_create_identity(path: &Path) -> Result<SecretKey> {
+    if path.exists() {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read identity file {}", path.display()))?;
+        let bytes = data_encoding::HEXLOWER
+            .decode(content.trim().as_bytes())
+            .context("identity file does not contain a valid hex key")?;
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("identity file must contain a 32-byte key"))?;
+        return Ok(SecretKey::from_bytes(&bytes));
+    }
+
+    let bytes: [u8; 32] = rand::random();
+    fs::write(path, data_encoding::HEXLOWER.encode(&bytes))
+        .with_context(|| format!("failed to write identity file {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    println!("> created new identity at {}", path.display());
+    Ok(SecretKey::from_bytes(&bytes))
+}
+
+// Config values only fill in flags the user didn't pass explicitly
+fn apply_config(cli: &mut Cli, matches: &ArgMatches, config: config::Config) -> Result<()> {
+    let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+    if unset("nick") && config.nick.is_some() {
+        cli.nick = config.nick;
+    }
+    if let Some(width) = config.width.filter(|_| unset("width")) {
+        cli.width = width;
+    }
+    if let Some(height) = config.height.filter(|_| unset("height")) {
+        cli.height = height;
+    }
+    if let Some(gamma) = config.gamma.filter(|_| unset("gamma")) {
+        cli.gamma = gamma;
+    }
+    if unset("identity") && config.identity.is_some() {
+        cli.identity = config.identity;
+    }
+    if let Some(url) = config.relay_url.filter(|_| unset("relay_url") && !cli.direct_only && !cli.lan) {
+        cli.relay_url = Some(url.parse().context("invalid relay_url in config")?);
+    }
+    Ok(())
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
+    display::install_panic_hook();
+    if let Err(e) = run().await {
+        display::restore_terminal();
+        eprintln!("Error: {:#}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+async fn run() -> Result<()> {
     // Initialize colored crate for Windows support
     #[cfg(windows)]
     let _ = control::set_virtual_terminal(true);
     
-    let cli = Cli::parse();
-    let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Commands::Completions { shell } = cli.commands {
+        clap_complete::generate(shell, &mut Cli::command(), "p2p-video-chat", &mut std::io::stdout());
+        return Ok(());
+    }
+    apply_config(&mut cli, &matches, config::Config::load()?)?;
+    // Flags beat the environment. Everything that draws color, the video escapes included,
+    // asks `SHOULD_COLORIZE`, so the override covers all of it
+    let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let force_color_env = std::env::var_os("FORCE_COLOR").is_some_and(|value| !value.is_empty() && value != "0");
+    if cli.no_color || (!cli.force_color && no_color_env) {
+        colored::control::set_override(false);
+    } else if cli.force_color || force_color_env {
+        colored::control::set_override(true);
+    }
+    if cli.gamma.is_nan() || cli.gamma <= 0.0 {
+        return Err(anyhow::anyhow!("--gamma must be greater than 0"));
+    }
+    if cli.width == 0 || cli.height == 0 {
+        return Err(anyhow::anyhow!("--width and --height must be greater than 0"));
+    }
+    if let Some(len) = cli.id_len {
+        ID_LEN.store(len as usize, Ordering::Relaxed);
+    }
+    let mut display_options = DisplayOptions {
+        gamma: cli.gamma,
+        ascii: cli.ascii,
+        dither: cli.dither,
+        color_bits: cli.color_bits,
+        truecolor: display::supports_truecolor(),
+        show_cursor: cli.show_cursor,
+        fill: cli.fill,
+        palette: cli.palette,
+        fixed_size: cli.fixed_size,
+    };
+    if !cli.chat && !cli.ascii && colored::control::SHOULD_COLORIZE.should_colorize() && !display::supports_truecolor() {
+        println!("> terminal does not report truecolor support (COLORTERM), using 256 colors");
+    }
+    if let Commands::TestCamera { out } = &cli.commands {
+        return test_camera(out);
+    }
+    let joins_video = !cli.chat && matches!(cli.commands, Commands::Open { .. } | Commands::Join { .. });
+    let shows_video = joins_video || matches!(cli.commands, Commands::Replay { .. });
+    if cli.auto_tune && shows_video {
+        let frame_budget = std::time::Duration::from_millis(cli.interval_ms);
+        display_options = tune::auto_tune(&display_options, frame_budget)?;
+    }
+    if let Commands::Replay { path, speed } = &cli.commands {
+        return replay(path, *speed, std::time::Duration::from_millis(cli.interval_ms), &display_options).await;
+    }
+    let events = match &cli.events {
+        Some(target) => EventSink::open(target)?,
+        None => EventSink::disabled(),
+    };
+
+    let mut builder = Endpoint::builder();
+    if cli.lan {
+        builder = builder.discovery_local_network();
+        println!("> discovery: local network (mDNS)");
+    } else if cli.no_discovery {
+        println!("> discovery: off (peers are only reached through ticket addresses)");
+    } else {
+        builder = builder.discovery_n0();
+        println!("> discovery: n0");
+    }
+    if cli.direct_only {
+        builder = builder.relay_mode(RelayMode::Disabled);
+        println!("> relay: disabled (direct connections only)");
+    } else if let Some(url) = &cli.relay_url {
+        builder = builder.relay_mode(RelayMode::Custom(RelayMap::from(url.clone())));
+        println!("> relay: {}", url);
+    } else if cli.lan {
+        builder = builder.relay_mode(RelayMode::Disabled);
+        println!("> relay: disabled (lan mode, direct connections only)");
+    } else {
+        println!("> relay: default n0 relays");
+    }
+    if let Some(path) = &cli.identity {
+        builder = builder.secret_key(load_or_create_identity(path)?);
+    }
+    match cli.bind {
+        Some(std::net::SocketAddr::V4(addr)) => {
+            builder = builder.bind_addr_v4(addr);
+            println!("> bind: {}", addr);
+        }
+        Some(std::net::SocketAddr::V6(addr)) => {
+            builder = builder.bind_addr_v6(addr);
+            println!("> bind: {}", addr);
+        }
+        None => {}
+    }
+    let endpoint = builder.bind().await.context(Failure::ConnectFailed)?;
+
+    let init_timeout = std::time::Duration::from_secs(cli.init_timeout);
+    if let Commands::Ping { ticket } = &cli.commands {
+        return ping(&endpoint, &read_ticket_arg(ticket)?, cli.prefer).await;
+    }
+    if let Commands::Ticket { topic } = &cli.commands {
+        let topic = match topic {
+            Some(topic) => topic_from_arg(topic),
+            None => TopicId::from_bytes(rand::random()),
+        };
+        let ticket = own_ticket(&endpoint, topic, cli.prefer, cli.nick.clone(), init_timeout).await;
+        println!("> room code: {}", ticket.to_short_code()?);
+        println!("> ticket: {}", ticket);
+        return Ok(());
+    }
 
     let gossip = Gossip::builder()
-        .max_message_size(10 * 1024 * 1024) 
+        .max_message_size(MAX_MESSAGE_SIZE) 
         .spawn(endpoint.clone());
     let _router = iroh::protocol::Router::builder(endpoint.clone())
         .accept(GOSSIP_ALPN, gossip.clone())
         .spawn();
 
-    let (topic_id, node_ids) = match cli.commands {
-        Commands::Open => (TopicId::from_bytes(rand::random()), Vec::new()),
-        Commands::Join { ticket } => {
-            let ticket = Ticket::from_code_or_full(&ticket)?;
-            
-            if let Some(first_node) = ticket.nodes.first() {
-                endpoint.add_node_addr(NodeAddr::new(first_node.node_id)
-                    .with_direct_addresses(first_node.direct_addresses.clone()))?;
-                (ticket.topic, vec![first_node.node_id])
-            } else {
+    let (topic_id, bootstrap, password, title) = match cli.commands {
+        Commands::Open { password, title, topic } => {
+            let topic = match topic {
+                Some(topic) => topic_from_arg(&topic),
+                None => TopicId::from_bytes(rand::random()),
+            };
+            (topic, Vec::new(), password, title)
+        }
+        Commands::Join { tickets, password } if tickets.len() > 1 => {
+            if cli.chat {
+                return Err(anyhow::anyhow!("--chat can only join one room at a time"));
+            }
+            let tickets = tickets
+                .iter()
+                .map(|arg| Ticket::from_code_or_full(&read_ticket_arg(arg)?))
+                .collect::<Result<Vec<_>>>()?;
+            if tickets.iter().any(|ticket| ticket.nodes.is_empty()) {
                 return Err(anyhow::anyhow!("Invalid ticket: no nodes found"));
             }
+            let options = watch::WatchOptions {
+                password,
+                nick: cli.nick.clone(),
+                keepalive_secs: cli.keepalive_secs,
+                prefer: cli.prefer,
+                display: display_options.clone(),
+            };
+            return watch::run(&endpoint, &gossip, tickets, events, options).await;
         }
-    };
-
-    let ticket = {
-        let me = endpoint.node_addr().initialized().await;
-        Ticket {
-            topic: topic_id,
-            nodes: vec![CompactNodeInfo {
-                node_id: me.node_id,
-                direct_addresses: me.direct_addresses.into_iter().collect(),
-            }],
+        Commands::Join { tickets, password } => {
+            let ticket = Ticket::from_code_or_full(&read_ticket_arg(&tickets[0])?)?;
+            if ticket.nodes.is_empty() {
+                return Err(anyhow::anyhow!("Invalid ticket: no nodes found"));
+            }
+            if let Some(host_name) = &ticket.host_name {
+                println!("> joining {}'s room", host_name);
+            }
+            
+            let bootstrap: Vec<NodeAddr> = ticket
+                .nodes
+                .iter()
+                .map(|node| node.to_node_addr(cli.prefer))
+                .collect();
+            for addr in &bootstrap {
+                endpoint.add_node_addr(addr.clone())?;
+            }
+            
+            (ticket.topic, bootstrap, password, None)
         }
+        Commands::Ping { .. }
+        | Commands::Ticket { .. }
+        | Commands::TestCamera { .. }
+        | Commands::Replay { .. }
+        | Commands::Completions { .. } => {
+            unreachable!("handled before joining a room")
+        }
+    };
+    let proof = password.as_deref().map(|password| password_proof(password, &topic_id));
+    // Only the host enforces the password, joiners just present theirs
+    let is_host = bootstrap.is_empty();
+    let rejoin = Rejoin {
+        endpoint: endpoint.clone(),
+        peers: bootstrap.clone(),
     };
+    let required_proof = if is_host { proof } else { None };
+
+    let ticket = own_ticket(&endpoint, topic_id, cli.prefer, cli.nick.clone(), init_timeout).await;
     
-    println!("> room code: {}", ticket.to_short_code()?);
-    println!("> {}... (max 2 people per room)", if node_ids.is_empty() {
+    let room_code = ticket.to_short_code()?;
+    if is_host && cli.print_ticket_json {
+        println!("{}", serde_json::to_string(&TicketJson { ticket: &ticket, short_code: &room_code })?);
+    } else {
+        println!("> room code: {}", room_code);
+    }
+    // Held for the whole session, on X11 the copied text goes away with its owner
+    let _clipboard = if cli.copy {
+        copy_to_clipboard(&ticket.to_string())
+    } else {
+        None
+    };
+    events.emit(AppEvent::RoomCode {
+        code: room_code.clone(),
+        ticket: ticket.to_string(),
+    });
+    println!("> {}... (max 2 people per room)", if is_host {
         "waiting for peer"
     } else {
         "connecting to peer"
     });
     
+    events.emit(AppEvent::Connecting {
+        topic: topic_id.to_string(),
+    });
+    
+    let spinner = Spinner::start();
     let (sender, receiver) = gossip
-        .subscribe_and_join(topic_id, node_ids)
-        .await?
+        .subscribe_and_join(topic_id, bootstrap.iter().map(|addr| addr.node_id).collect())
+        .await
+        .context(Failure::ConnectFailed)?
         .split();
+    drop(spinner);
     println!("> connected!");
+    events.emit(AppEvent::Connected);
+    spawn_keepalive(sender.clone(), endpoint.node_id(), cli.keepalive_secs);
+    let activity = Activity::default();
 
-    // Initialize camera with Windows COM workaround
-    println!("> initializing camera...");
+    // Three missed keepalives in a row means the peer is gone, even without a NeighborDown
+    let peer_timeout = (cli.keepalive_secs > 0)
+        .then(|| std::time::Duration::from_secs(cli.keepalive_secs.saturating_mul(3)));
+    let chat_options = chat::ChatOptions {
+        nick: cli.nick,
+        log_file: cli.log_file,
+        scrollback: cli.scrollback,
+        proof,
+        title: title.clone(),
+        is_host,
+        rejoin: rejoin.clone(),
+        peer_timeout,
+        idle_timeout_secs: cli.idle_timeout,
+    };
+    if cli.chat {
+        return chat::run(endpoint, sender, receiver, room_code, events, activity, chat_options).await;
+    }
+
+    let source_name = if cli.image.is_some() {
+        "image"
+    } else if cli.screen {
+        "screen"
+    } else {
+        "camera"
+    };
+    println!("> initializing {}...", source_name);
+    let interval = std::time::Duration::from_millis(cli.interval_ms);
+    let open_source: OpenSource = if let Some(path) = cli.image.clone() {
+        Arc::new(move || -> Result<Box<dyn FrameSource>> { Ok(Box::new(StillImage::open(&path, interval)?)) })
+    } else if cli.screen {
+        Arc::new(move || -> Result<Box<dyn FrameSource>> { Ok(Box::new(ScreenCapture::new(interval)?)) })
+    } else {
+        Arc::new(|| -> Result<Box<dyn FrameSource>> { Ok(Box::new(CameraCapture::new()?)) })
+    };
     
-    #[cfg(target_os = "windows")]
-    {
-        unsafe {
-            CoUninitialize();
-            std::thread::sleep(std::time::Duration::from_millis(100));
-        
-            let hr = CoInitializeEx(
-                None,
-                COINIT_APARTMENTTHREADED
-            );
-            
-            if hr.is_err() && hr.0 != 1 {
-                eprintln!("Warning: Could not set apartment threading, trying multithreaded: {:?}", hr);
-                
-                CoUninitialize();
-                std::thread::sleep(std::time::Duration::from_millis(50));
-                
-                let hr2 = CoInitializeEx(
-                    None,
-                    COINIT_MULTITHREADED
+    let mut camera = match CaptureWorker::start(open_source, source_name) {
+        Ok((worker, info)) => {
+            println!("> {} ready ({}x{})", source_name, info.width, info.height);
+            if info.fps > 0 && cli.interval_ms < 1000 / info.fps as u64 {
+                println!(
+                    "> warning: the camera only delivers {} fps, video won't go faster than that whatever --interval-ms {} asks for",
+                    info.fps, cli.interval_ms
                 );
-                
-                if hr2.is_err() && hr2.0 != 1 {
-                    eprintln!("Warning: Could not initialize COM at all: {:?}", hr2);
-                }
             }
+            worker
         }
-    }
-    
-    let mut camera = match CameraCapture::new() {
-        Ok(cam) => {
-            Some(cam)
-        },
         Err(e) => {
-            #[cfg(target_os = "windows")]
-            {
-                println!("> warning: failed to initialize camera: {}", e);
+            events.emit(AppEvent::Error {
+                message: format!("failed to initialize {}: {}", source_name, e),
+            });
+            println!("> warning: failed to initialize {}: {}", source_name, e);
+            if cfg!(target_os = "windows") && source_name == "camera" {
                 println!("> this is often caused by Windows Media Foundation issues");
                 println!("> troubleshooting steps:");
                 println!(">   1. ensure no other applications are using the camera");
                 println!(">   2. try running as administrator");
                 println!(">   3. check camera permissions in windows privacy settings");
                 println!(">   4. restart the application");
-                println!("> will send placeholder frames and can still receive video from peers");
             }
-            #[cfg(not(target_os = "windows"))]
-            {
-                println!("> warning: failed to initialize camera: {}", e);
-                println!("> will send placeholder frames and can still receive video from peers");
+            if cli.require_camera {
+                return Err(e).context(Failure::CameraUnavailable);
             }
-            None
+            // A checkerboard nobody asked for is less useful than being able to talk
+            println!("> {} unavailable — falling back to text chat", source_name);
+            return chat::run(endpoint, sender, receiver, room_code, events, activity, chat_options).await;
         }
     };
 
-    let mut display: Option<TerminalDisplay> = None;
+    let mut feed = FeedSwitch::default();
+    let local_caps = Capabilities::video(cli.width, cli.height);
 
     sender.broadcast(Message::new(MessageBody::AboutMe {
         from: endpoint.node_id(),
+        proof,
+        view: Some(view_size(display_options.fixed_size)),
+        caps: local_caps,
+        nick: chat_options.nick.clone(),
     }).to_vec().into()).await?;
+    if let Some(title) = &title {
+        println!("> room title: {}", title);
+        send_room_info(&sender, endpoint.node_id(), title).await;
+    }
 
-    let (frame_tx, mut frame_rx) = tokio::sync::mpsc::unbounded_channel::<(Vec<u8>, u32, u32)>();
+    let (frame_tx, mut frame_rx) = tokio::sync::mpsc::unbounded_channel::<(NodeId, Frame)>();
+    let (caption_tx, mut caption_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
     
     let sender_clone = sender.clone();
     let my_id = endpoint.node_id();
-    tokio::spawn(subscribe_loop(receiver, sender_clone.clone(), my_id, frame_tx));
+    let (kick_tx, kick_rx) = tokio::sync::mpsc::unbounded_channel::<NodeId>();
+    let (view_tx, mut view_rx) = tokio::sync::mpsc::unbounded_channel::<(NodeId, u32, u32)>();
+    let mut peer_views: HashMap<NodeId, (u32, u32)> = HashMap::new();
+    let (caps_tx, mut caps_rx) = tokio::sync::mpsc::unbounded_channel::<(NodeId, Capabilities)>();
+    let (presence_tx, mut presence_rx) = tokio::sync::mpsc::unbounded_channel::<(NodeId, PresenceState)>();
+    let (occupancy_tx, mut occupancy_rx) = tokio::sync::mpsc::unbounded_channel::<usize>();
+    let (full_frame_tx, mut full_frame_rx) = tokio::sync::mpsc::unbounded_channel::<NodeId>();
+    let mut peer_caps: HashMap<NodeId, Capabilities> = HashMap::new();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let mut subscribe_task = spawn_until_shutdown(
+        subscribe_loop(
+            receiver,
+            sender_clone.clone(),
+            my_id,
+            RoomSettings { required_proof, title, rejoin, peer_timeout },
+            activity.clone(),
+            VideoChannels {
+                frames: frame_tx,
+                captions: caption_tx,
+                kicks: kick_rx,
+                views: view_tx,
+                caps: caps_tx,
+                presence: presence_tx,
+                full_frame_requests: full_frame_tx,
+                occupancy: occupancy_tx,
+            },
+            events.clone(),
+        ),
+        shutdown_rx,
+    );
+    let mut muted: std::collections::HashSet<NodeId> = std::collections::HashSet::new();
 
-    let keepalive_sender = sender.clone();
-    let keepalive_id = my_id;
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
-        loop {
-            interval.tick().await;
-            let _ = keepalive_sender.broadcast(Message::new(MessageBody::KeepAlive {
-                from: keepalive_id,
-            }).to_vec().into()).await;
-        }
-    });
+    let mut interval = tokio::time::interval(std::time::Duration::from_millis(cli.interval_ms));
+    let mut last_frame: Option<Frame> = None;
+    let mut thumbnails = ThumbnailGate::default();
+    let mut last_full_request: Option<std::time::Instant> = None;
+    let mut presence = PresenceState::Active;
+    let mut last_motion = std::time::Instant::now();
 
-    let mut interval = tokio::time::interval(std::time::Duration::from_millis(33));
-    let mut last_frame: Option<Vec<u8>> = None;
+    let jitter = std::time::Duration::from_millis(cli.jitter_ms);
+    // Enough room for the configured delay at the target framerate, plus a little slack
+    let jitter_depth = (cli.jitter_ms / cli.interval_ms) as usize + 2;
+    let mut jitter_buffer: VecDeque<(std::time::Instant, Frame)> = VecDeque::new();
+    let mut playout_interval = tokio::time::interval(std::time::Duration::from_millis(cli.interval_ms));
+    playout_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut crossfade = Crossfade::new(if cli.interpolate { 3 } else { 0 });
     
-    let create_error_frame = || {
-        let width = 640u32;
-        let height = 480u32;
-        let mut frame_data = Vec::with_capacity((width * height * 3) as usize);
-        
-        let center_x = width / 2;
-        let center_y = height / 2;
-        
-        for y in 0..height {
-            for x in 0..width {
-                let dx = (x as i32 - center_x as i32).abs();
-                let dy = (y as i32 - center_y as i32).abs();
-                let dist = ((dx * dx + dy * dy) as f64).sqrt();
-                
-                if dist < 50.0 {
-                    frame_data.extend_from_slice(&[255, 255, 255]);
-                } else if (x / 40) % 2 == (y / 40) % 2 {
-                    frame_data.extend_from_slice(&[180, 40, 40]);
-                } else {
-                    frame_data.extend_from_slice(&[120, 20, 20]);
-                }
-            }
-        }
-        
-        (frame_data, width, height)
-    };
+    let max_send_w = cli.width;
+    let max_send_h = cli.height;
+    let motion_threshold = cli.motion_threshold.min(100);
+    let mut capture_gate = CaptureGate::new();
 
-    let reduce_frame_size = |frame: &[u8], orig_w: u32, orig_h: u32, new_w: u32, new_h: u32| -> Vec<u8> {
-        let mut reduced = Vec::with_capacity((new_w * new_h * 3) as usize);
-        
-        for y in 0..new_h {
-            for x in 0..new_w {
-                let orig_x = ((x as f32 / new_w as f32) * orig_w as f32) as u32;
-                let orig_y = ((y as f32 / new_h as f32) * orig_h as f32) as u32;
-                
-                let orig_x = orig_x.min(orig_w - 1);
-                let orig_y = orig_y.min(orig_h - 1);
-                
-                let idx = ((orig_y * orig_w + orig_x) * 3) as usize;
-                if idx + 2 < frame.len() {
-                    reduced.extend_from_slice(&[frame[idx], frame[idx + 1], frame[idx + 2]]);
-                } else {
-                    reduced.extend_from_slice(&[0, 0, 0]);
-                }
-            }
-        }
-        
-        reduced
-    };
+    let mut stats_interval = tokio::time::interval(std::time::Duration::from_secs(5));
+    let mut stats = Stats::new();
+    let mut recorder = cli.record.as_deref().map(record::Recorder::create).transpose()?;
+    let frame_in_flight = Arc::new(AtomicBool::new(false));
+    let (broadcast_error_tx, mut broadcast_error_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let mut last_broadcast_error: Option<String> = None;
+    // Lowered whenever a frame turns out too big for gossip, and never raised again
+    let mut size_limit: Option<(u32, u32)> = None;
 
-    let mut frame_counter = 0u32;
-    let mut _last_frame_time = std::time::Instant::now();
+    let (command_tx, mut command_rx) = tokio::sync::mpsc::unbounded_channel::<VideoCommand>();
+    std::thread::spawn(move || video_input_loop(command_tx));
+    let mut resize_rx = display::watch_resize();
+    let mut paused = false;
+    let mut show_stats = cli.stats;
+    let controls = vec![
+        "controls: p + enter to pause sending, h + enter to toggle stats, ? + enter for help, q + enter to quit".to_string(),
+        format!("mute/unmute <peer> + enter to hide or show a peer's video{}", if is_host {
+            ", kick <peer> + enter to remove them"
+        } else {
+            ""
+        }),
+    ];
+    for line in &controls {
+        println!("> {}", line);
+    }
+    let mut help = vec![
+        "p      pause or resume sending video".to_string(),
+        "h      show or hide stats".to_string(),
+        "?      show or hide this help".to_string(),
+        "mute <peer>, unmute <peer>".to_string(),
+        "       hide or show a peer's video".to_string(),
+    ];
+    if is_host {
+        help.push("kick <peer>".to_string());
+        help.push("       remove a peer from the room".to_string());
+    }
+    help.push("q      quit".to_string());
+    help.push(String::new());
+    help.push("each followed by enter".to_string());
+    let mut display = Some(TerminalDisplay::lobby(&room_code, controls, &display_options));
+    let lobby_started = std::time::Instant::now();
+    let mut lobby_interval = tokio::time::interval(std::time::Duration::from_millis(120));
+    // Tells a peer who switched their camera off apart from one who dropped out
+    let mut last_peer_frame: Option<std::time::Instant> = None;
+    let mut peer_camera_off = false;
+    // The peer whose video was on screen until it left, while its placeholder shows
+    let mut gone_peer: Option<NodeId> = None;
+    let mut gone_interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    let mut overlay_interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    let idle = idle_timeout(activity.clone(), cli.idle_timeout);
+    tokio::pin!(idle);
+    let mut idle_ended = None;
+    // Set when the receive loop finishes first, e.g. because the room is full or was closed
+    let mut subscribe_result = None;
 
     loop {
         tokio::select! {
             _ = interval.tick() => {
-                if let Some(ref mut cam) = camera {
-                    frame_counter += 1;
-                    
-                    let should_capture = if cam.is_healthy() {
-                        true
-                    } else {
-                        frame_counter % 2 == 0
-                    };
-                    
-                    if should_capture {
-                        let (width, height) = cam.dimensions();
-                        match cam.get_frame() {
-                            Ok(frame) => {
-                                let now = std::time::Instant::now();
-                                _last_frame_time = now;
-                                
-                                if frame.len() >= (width * height * 3) as usize {
-                                    let reduced_frame = reduce_frame_size(frame, width, height, 640, 480);
-
-                                    let should_send = if let Some(ref last) = last_frame {
-                                        frames_differ(&reduced_frame, last, 1)
-                                    } else {
-                                        true
-                                    };
-                                    
-                                    if should_send {
-                                        let frame_data = reduced_frame.clone();
-                                        
-                                        let message = Message::new(MessageBody::VideoFrame {
-                                            from: endpoint.node_id(),
-                                            frame_data,
-                                            width: 640,
-                                            height: 480,
-                                        });
-                                        let message_bytes = message.to_vec();
-                                        let _ = sender.broadcast(message_bytes.into()).await;
-                                        
-                                        last_frame = Some(reduced_frame);
-                                    }
+                let state = if paused {
+                    PresenceState::CameraOff
+                } else if last_motion.elapsed() >= AWAY_AFTER {
+                    PresenceState::Away
+                } else {
+                    PresenceState::Active
+                };
+                if state != presence {
+                    presence = state;
+                    send_presence(&sender, endpoint.node_id(), presence).await;
+                }
+                if paused {
+                    continue;
+                }
+                let negotiated = peer_caps.values().fold(local_caps, |acc, caps| acc.common(caps));
+                // Nobody on the other end could decode what we'd send
+                if !negotiated.supports(ENCODING_RAW) {
+                    continue;
+                }
+                if capture_gate.should_capture(camera.is_healthy()) {
+                    match camera.poll() {
+                        None => {}
+                        // Dimensions come from the decoded image, so the length has to match exactly
+                        Some(Ok(frame)) if frame.is_valid() => {
+                            // No point sending more detail than the largest peer terminal can show
+                            let largest_view = peer_views
+                                .values()
+                                .copied()
+                                .reduce(|(aw, ah), (w, h)| (aw.max(w), ah.max(h)));
+                            let (max_w, max_h) = negotiated.max_resolution;
+                            let (max_w, max_h) = size_limit.map_or((max_w, max_h), |(w, h)| (max_w.min(w), max_h.min(h)));
+                            let (cap_w, cap_h) = largest_view
+                                .map_or((max_w, max_h), |(w, h)| (max_w.min(w), max_h.min(h)));
+                            let reduced_frame = frame.scaled(cap_w, cap_h);
+                            let next_frame = if cli.thumbnails {
+                                thumbnails.next(reduced_frame, last_frame.as_ref(), motion_threshold)
+                            } else if last_frame.as_ref().is_none_or(|last| reduced_frame.differs_from(last, motion_threshold)) {
+                                Some(reduced_frame)
+                            } else {
+                                None
+                            };
+
+                            let Some(reduced_frame) = next_frame else {
+                                stats.record_unchanged();
+                                continue;
+                            };
+                            last_motion = std::time::Instant::now();
+                            if frame_in_flight.load(Ordering::Acquire) {
+                                stats.record_skipped();
+                            } else {
+                                let message = Message::new(MessageBody::VideoFrame {
+                                    from: endpoint.node_id(),
+                                    checksum: Some(reduced_frame.checksum()),
+                                    frame: reduced_frame.clone(),
+                                });
+                                let message_bytes = message.to_vec();
+                                if message_bytes.len() > MAX_MESSAGE_SIZE {
+                                    let limit = ((reduced_frame.width * 3 / 4).max(1), (reduced_frame.height * 3 / 4).max(1));
+                                    let notice = format!(
+                                        "a {}x{} frame is {} bytes, over the {} byte message limit; sending at most {}x{} from now on",
+                                        reduced_frame.width,
+                                        reduced_frame.height,
+                                        message_bytes.len(),
+                                        MAX_MESSAGE_SIZE,
+                                        limit.0,
+                                        limit.1
+                                    );
+                                    eprintln!("{}", notice);
+                                    events.emit(AppEvent::Error { message: notice });
+                                    size_limit = Some(limit);
+                                    continue;
                                 }
-                            },
-                            Err(e) => {
-                                eprintln!("Error capturing frame: {}", e);
-                                let (error_frame, error_width, error_height) = create_error_frame();
-                                let frame_data = error_frame.clone(); 
+                                stats.record_sent(message_bytes.len());
+                                broadcast_frame(&sender, &frame_in_flight, message_bytes, &broadcast_error_tx);
+                                
+                                last_frame = Some(reduced_frame);
+                            }
+                        }
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => {
+                            capture_gate.capture_failed();
+                            eprintln!("Error capturing frame: {}", e);
+                            events.emit(AppEvent::Error {
+                                message: format!("error capturing frame: {}", e),
+                            });
+                            if frame_in_flight.load(Ordering::Acquire) {
+                                stats.record_skipped();
+                            } else if !cli.require_camera {
+                                let frame = Frame::placeholder();
                                 let message = Message::new(MessageBody::VideoFrame {
                                     from: endpoint.node_id(),
-                                    frame_data,
-                                    width: error_width,
-                                    height: error_height,
+                                    checksum: Some(frame.checksum()),
+                                    frame,
                                 });
                                 let message_bytes = message.to_vec();
-                                let _ = sender.broadcast(message_bytes.into()).await;
+                                stats.record_sent(message_bytes.len());
+                                broadcast_frame(&sender, &frame_in_flight, message_bytes, &broadcast_error_tx);
                             }
                         }
                     }
-                } else {
-                    let (error_frame, error_width, error_height) = create_error_frame();
-                    let frame_data = error_frame.clone();
-                    
-                    let should_send = if let Some(ref last) = last_frame {
-                        frames_differ(&frame_data, last, 5)
-                    } else {
-                        true
-                    };
-                    
-                    if should_send {
-                        let message = Message::new(MessageBody::VideoFrame {
-                            from: endpoint.node_id(),
-                            frame_data: frame_data.clone(),
-                            width: error_width,
-                            height: error_height,
-                        });
-                        let message_bytes = message.to_vec();
-                        let _ = sender.broadcast(message_bytes.into()).await;
-                        
-                        last_frame = Some(frame_data);
-                    }
                 }
             }
-            Some((frame_data, width, height)) = frame_rx.recv() => {
-                if display.is_none() {
-                    display = Some(TerminalDisplay::new(width, height));
-                    println!("> receiving video from peer...");
-                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            Some((from, frame)) = frame_rx.recv() => {
+                stats.record_received(frame.data.len());
+                if muted.contains(&from) {
+                    continue;
                 }
                 
-                if let Some(ref mut disp) = display {
-                    if let Err(e) = disp.show_frame(&frame_data) {
-                        eprintln!("Display error: {}", e);
+                let Some(frame) = feed.offer(from, frame) else {
+                    continue;
+                };
+                last_peer_frame = Some(std::time::Instant::now());
+                if let Some(rec) = recorder.as_mut() {
+                    if let Err(e) = rec.record(&frame) {
+                        eprintln!("Recording stopped: {}", e);
+                        recorder = None;
                     }
                 }
-            }
-        }
-    }
-}
-
-async fn subscribe_loop(
-    mut receiver: GossipReceiver, 
-    sender: GossipSender, 
-    my_node_id: NodeId,
-    frame_tx: tokio::sync::mpsc::UnboundedSender<(Vec<u8>, u32, u32)>
-) -> Result<()> {
-    let mut connected_peers = std::collections::HashSet::new();
-    let mut rejected_peers = std::collections::HashSet::new();
-    
-    while let Some(event) = receiver.try_next().await? {
-        if let Event::Received(msg) = event {
-            match Message::from_bytes(&msg.content) {
-                Ok(message) => {
-                    match message.body {
-                MessageBody::AboutMe { from } => {
-                    if from == my_node_id {
-                        continue;
-                    }
-                    
-                    if rejected_peers.contains(&from) {
-                        let _ = sender.broadcast(Message::new(MessageBody::RoomFull {
-                            from: my_node_id,
-                            target: from,
-                        }).to_vec().into()).await;
-                        continue;
+                peer_camera_off = false;
+                gone_peer = None;
+                // A thumbnail on a terminal that could show more, so ask for the real thing
+                let (view_w, view_h) = view_size(display_options.fixed_size);
+                let is_thumbnail = frame.width <= THUMBNAIL_SIZE.0 && frame.height <= THUMBNAIL_SIZE.1;
+                if is_thumbnail
+                    && (frame.width < view_w || frame.height < view_h)
+                    && last_full_request.is_none_or(|at| at.elapsed() >= FULL_FRAME_REQUEST_INTERVAL)
+                {
+                    last_full_request = Some(std::time::Instant::now());
+                    let _ = sender.broadcast(Message::new(MessageBody::RequestFullFrame {
+                        from: endpoint.node_id(),
+                        target: from,
+                    }).to_vec().into()).await;
+                }
+                if jitter.is_zero() {
+                    if !crossfade.start(&frame.data, frame.width, frame.height) {
+                        render_frame(&mut display, &display_options, &events, &frame.data, frame.width, frame.height).await;
+                    } else if let Some((blended, width, height)) = crossfade.next_frame() {
+                        render_frame(&mut display, &display_options, &events, blended, width, height).await;
                     }
-                    
-                    if connected_peers.len() >= 1 {
-                        println!("{} tried to join but room is full. Rejecting connection.", from.fmt_short());
-                        rejected_peers.insert(from);
-                        for _ in 0..3 {
-                            let _ = sender.broadcast(Message::new(MessageBody::RoomFull {
-                                from: my_node_id,
-                                target: from,
-                            }).to_vec().into()).await;
-                            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    continue;
+                }
+                if jitter_buffer.len() >= jitter_depth {
+                    jitter_buffer.pop_front();
+                }
+                jitter_buffer.push_back((std::time::Instant::now(), frame));
+            }
+            _ = playout_interval.tick() => {
+                // Release at most one frame per tick so clumped arrivals are spread back out
+                let ready = jitter_buffer
+                    .front()
+                    .is_some_and(|(arrived, ..)| arrived.elapsed() >= jitter);
+                if ready {
+                    if let Some((_, frame)) = jitter_buffer.pop_front() {
+                        if !crossfade.start(&frame.data, frame.width, frame.height) {
+                            render_frame(&mut display, &display_options, &events, &frame.data, frame.width, frame.height).await;
+                            continue;
                         }
-                    } else {
-                        connected_peers.insert(from);
-                        println!("{} has joined ({}/2 people in room)", from.fmt_short(), connected_peers.len() + 1);
-                    }
-                },
-                MessageBody::VideoFrame { from, frame_data, width, height } => {
-                    if from == my_node_id {
-                        continue;
-                    }
-                    
-                    if rejected_peers.contains(&from) {
-                        let _ = sender.broadcast(Message::new(MessageBody::RoomFull {
-                            from: my_node_id,
-                            target: from,
-                        }).to_vec().into()).await;
-                        continue;
                     }
-                    
-                    let frame_data_raw = frame_data.clone();
-                    
-                    if connected_peers.contains(&from) {
-                        let _ = frame_tx.send((frame_data_raw, width, height));
-                    } else if connected_peers.len() < 1 {
-                        connected_peers.insert(from);
-                        println!("{} has joined ({}/2 people in room)", from.fmt_short(), connected_peers.len() + 1);
-                        
-                        let _ = frame_tx.send((frame_data_raw, width, height));
-                    } else {
-                        rejected_peers.insert(from);
-                        let _ = sender.broadcast(Message::new(MessageBody::RoomFull {
-                            from: my_node_id,
-                            target: from,
-                        }).to_vec().into()).await;
+                }
+                if let Some((blended, width, height)) = crossfade.next_frame() {
+                    render_frame(&mut display, &display_options, &events, blended, width, height).await;
+                }
+            }
+            _ = stats_interval.tick() => {
+                stats.tick();
+                events.emit(stats.to_event());
+                if show_stats {
+                    let line = stats.summary();
+                    match display {
+                        Some(ref mut disp) => disp.set_status(Some(line)),
+                        None => println!("> {}", line),
                     }
-                },
-                MessageBody::RoomFull { from, target } => {
-                    if from != my_node_id && target == my_node_id {
-                        println!("Room you tried to join is full. Only 2 people allowed per room.");
-                        std::process::exit(1);
+                }
+            }
+            _ = lobby_interval.tick(), if display.as_ref().is_some_and(|disp| disp.in_lobby()) => {
+                if let Some(ref mut disp) = display {
+                    if let Err(e) = disp.render_lobby(lobby_started.elapsed()) {
+                        eprintln!("Display error: {}", e);
                     }
-                },
-                MessageBody::KeepAlive { from } => {
-                    if from == my_node_id {
-                        continue;
+                }
+            }
+            Some(error) = broadcast_error_rx.recv() => {
+                // A broken send tends to fail the same way every frame, so only changes are reported
+                if last_broadcast_error.as_deref() != Some(error.as_str()) {
+                    eprintln!("Failed to broadcast frame: {}", error);
+                    events.emit(AppEvent::Error {
+                        message: format!("failed to broadcast frame: {}", error),
+                    });
+                    last_broadcast_error = Some(error);
+                }
+            }
+            Some(caption) = caption_rx.recv() => {
+                match display {
+                    Some(ref mut disp) => disp.set_caption(caption),
+                    None => println!("{}", caption),
+                }
+            }
+            Some(()) = resize_rx.recv() => {
+                if display_options.fixed_size.is_some() {
+                    continue;
+                }
+                if let Some(ref mut disp) = display {
+                    if let Err(e) = disp.handle_resize() {
+                        eprintln!("Display error: {}", e);
                     }
-                    if !rejected_peers.contains(&from) && connected_peers.len() < 1 {
-                        connected_peers.insert(from);
+                }
+                let (width, height) = view_size(display_options.fixed_size);
+                let _ = sender.broadcast(Message::new(MessageBody::ViewSize {
+                    from: endpoint.node_id(),
+                    width,
+                    height,
+                }).to_vec().into()).await;
+            }
+            Some((peer, caps)) = caps_rx.recv() => {
+                peer_caps.insert(peer, caps);
+                // A peer that just joined has missed our last presence change
+                if presence != PresenceState::Active {
+                    send_presence(&sender, endpoint.node_id(), presence).await;
+                }
+            }
+            Some((peer, state)) = presence_rx.recv() => {
+                let line = format!("{} {}", short_id(&peer), state.describe());
+                match display {
+                    Some(ref mut disp) => {
+                        disp.set_presence((state != PresenceState::Active).then_some(line));
+                    }
+                    None => println!("> {}", line),
+                }
+                if feed.shown == Some(peer) && gone_peer.is_none() {
+                    if state == PresenceState::CameraOff {
+                        peer_camera_off = true;
+                        show_placeholder(&mut display, Some(Placeholder::CameraOff {
+                            peer: short_id(&peer),
+                        }));
+                    } else if peer_camera_off {
+                        peer_camera_off = false;
+                        show_placeholder(&mut display, None);
                     }
                 }
             }
-        },
-        Err(e) => {
-            eprintln!("Failed to decode message: {}", e);
+            Some(_) = full_frame_rx.recv() => {
+                thumbnails.request_full();
+            }
+            Some(count) = occupancy_rx.recv() => {
+                let line = format!("{}/{} in room", count, ROOM_CAPACITY);
+                match display {
+                    Some(ref mut disp) => disp.set_occupancy(line),
+                    None => println!("> {}", line),
+                }
+                // Only the keepalive timeout or a kick lowers the count, so the peer is really gone
+                if count == 1 && feed.shown.is_some() {
+                    let left = feed.shown;
+                    peer_camera_off = false;
+                    jitter_buffer.clear();
+                    match feed.shown_left() {
+                        Some(frame) => {
+                            last_peer_frame = Some(std::time::Instant::now());
+                            show_placeholder(&mut display, None);
+                            render_frame(&mut display, &display_options, &events, &frame.data, frame.width, frame.height).await;
+                        }
+                        None => {
+                            gone_peer = left;
+                            gone_interval.reset_immediately();
+                        }
+                    }
+                }
+            }
+            _ = overlay_interval.tick(), if display.as_ref().is_some_and(|disp| disp.overlay_expired()) => {
+                show_overlay(&mut display, None);
+            }
+            _ = gone_interval.tick(), if gone_peer.is_some() => {
+                if let Some(peer) = gone_peer {
+                    show_placeholder(&mut display, Some(Placeholder::Disconnected {
+                        peer: short_id(&peer),
+                        silent_secs: last_peer_frame.map_or(0, |at| at.elapsed().as_secs()),
+                    }));
+                }
+            }
+            Some((peer, width, height)) = view_rx.recv() => {
+                let previous = peer_views.insert(peer, (width, height));
+                if previous.is_none() && (width < max_send_w / 2 || height < max_send_h / 2) {
+                    let notice = format!(
+                        "> {} has a small terminal, sending at most {}x{}",
+                        short_id(&peer),
+                        width.min(max_send_w),
+                        height.min(max_send_h)
+                    );
+                    match display {
+                        Some(ref mut disp) => disp.set_caption(notice),
+                        None => println!("{}", notice),
+                    }
+                }
+            }
+            _ = tokio::signal::ctrl_c() => break,
+            e = &mut idle => {
+                idle_ended = Some(e);
+                break;
+            }
+            result = &mut subscribe_task => {
+                subscribe_result = Some(result);
+                break;
+            }
+            Some(command) = command_rx.recv() => {
+                // Whatever is typed next closes the help, and `?` only opens it when it was closed
+                let help_open = display.as_ref().is_some_and(|disp| disp.has_overlay());
+                let open_help = matches!(command, VideoCommand::Help) && !help_open;
+                if help_open || open_help {
+                    show_overlay(&mut display, open_help.then(|| help.clone()));
+                }
+                let notice = match command {
+                    VideoCommand::TogglePause => {
+                        paused = !paused;
+                        last_motion = std::time::Instant::now();
+                        if paused { "video paused".to_string() } else { "video resumed".to_string() }
+                    }
+                    VideoCommand::ToggleStats => {
+                        show_stats = !show_stats;
+                        if !show_stats {
+                            if let Some(ref mut disp) = display {
+                                disp.set_status(None);
+                            }
+                        }
+                        if show_stats { "stats on".to_string() } else { "stats off".to_string() }
+                    }
+                    VideoCommand::Mute(prefix) => {
+                        let known = feed.known();
+                        match resolve_peer(&prefix, known) {
+                            Ok(peer) => {
+                                muted.insert(peer);
+                                format!("muted {}", short_id(&peer))
+                            }
+                            Err(e) => e,
+                        }
+                    }
+                    VideoCommand::Unmute(prefix) => {
+                        match resolve_peer(&prefix, muted.iter().copied()) {
+                            Ok(peer) => {
+                                muted.remove(&peer);
+                                format!("unmuted {}", short_id(&peer))
+                            }
+                            Err(e) => e,
+                        }
+                    }
+                    VideoCommand::Kick(_) if !is_host => "only the host can kick peers".to_string(),
+                    VideoCommand::Kick(prefix) => {
+                        let known = feed.known();
+                        match resolve_peer(&prefix, known) {
+                            Ok(peer) => {
+                                let _ = kick_tx.send(peer);
+                                peer_views.remove(&peer);
+                                peer_caps.remove(&peer);
+                                if feed.forget(peer) {
+                                    jitter_buffer.clear();
+                                }
+                                format!("kicked {}", short_id(&peer))
+                            }
+                            Err(e) => e,
+                        }
+                    }
+                    VideoCommand::Help | VideoCommand::Dismiss => continue,
+                    VideoCommand::Quit => break,
+                };
+                match display {
+                    Some(ref mut disp) => disp.set_caption(format!("> {}", notice)),
+                    None => println!("> {}", notice),
+                }
+            }
         }
     }
+
+    drop(display.take());
+    stats.tick();
+    println!("> {}", stats.summary());
+    let _ = shutdown_tx.send(true);
+    let subscribe_result = match subscribe_result {
+        Some(result) => result,
+        None => subscribe_task.await,
+    };
+    if let Ok(Err(e)) = subscribe_result {
+        // The room ended for us, main maps it to its exit code
+        if e.downcast_ref::<Failure>().is_some() {
+            return Err(e);
         }
+        eprintln!("Receive loop ended with an error: {}", e);
     }
+    if is_host {
+        close_room(&sender, endpoint.node_id()).await;
+    }
+    match idle_ended {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+async fn render_frame(
+    display: &mut Option<TerminalDisplay>,
+    options: &DisplayOptions,
+    events: &EventSink,
+    frame_data: &[u8],
+    width: u32,
+    height: u32,
+) {
+    if display.is_none() {
+        *display = Some(TerminalDisplay::new(width, height, options));
+        println!("> receiving video from peer...");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    if let Some(disp) = display {
+        // Peer changed resolution, the old layout would index the frame with the wrong stride
+        disp.reconfigure(width, height);
+        if let Err(e) = disp.show_frame(frame_data) {
+            eprintln!("Display error: {}", e);
+            events.emit(AppEvent::Error {
+                message: format!("display error: {}", e),
+            });
+        }
+    }
+}
+
+// Plays a --record session at its recorded pace through the same renderer as live video
+async fn replay(path: &Path, speed: f32, min_gap: std::time::Duration, options: &DisplayOptions) -> Result<()> {
+    if speed.is_nan() || speed <= 0.0 {
+        return Err(anyhow::anyhow!("--speed must be greater than 0"));
+    }
+    let mut recording = record::Recording::open(path)?;
+    let schedule = record::schedule(&recording.entries, speed, min_gap);
+    let Some(first) = recording.entries.first() else {
+        return Err(anyhow::anyhow!("recording {} has no frames", path.display()));
+    };
+    println!("> replaying {} frames from {}", schedule.len(), path.display());
+    let mut display = TerminalDisplay::new(first.width, first.height, options);
+    let started = tokio::time::Instant::now();
+    for (at, index) in schedule {
+        tokio::select! {
+            _ = tokio::time::sleep_until(started + at) => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        let entry = recording.entries[index].clone();
+        let frame = recording.frame(&entry)?;
+        display.reconfigure(frame.width, frame.height);
+        display.show_frame(&frame.data)?;
+    }
+    drop(display);
+    println!("> replay finished");
     Ok(())
 }
 
+// With --thumbnails, what resolution each captured frame goes out at: small while the picture
+// moves, full when a peer asks, when it changes a lot, or once it has settled
+#[derive(Default)]
+struct ThumbnailGate {
+    // The last frame sent at full resolution
+    last_full: Option<Frame>,
+    full_requested: bool,
+    // Captures in a row that looked like the thumbnail already sent
+    still: u32,
+}
+
+impl ThumbnailGate {
+    fn request_full(&mut self) {
+        self.full_requested = true;
+    }
+
+    // `frame` is already scaled to what peers can show. Returns what to send, or `None` when
+    // it would look the same as `last_sent`
+    fn next(&mut self, frame: Frame, last_sent: Option<&Frame>, motion_threshold: u8) -> Option<Frame> {
+        let (candidate, is_thumbnail) = match &self.last_full {
+            Some(full) if !self.full_requested && !frame.differs_from(full, FULL_FRAME_CHANGE) => {
+                // Back to how the last full frame looked, so peers get that sharper picture again
+                if frame.differs_from(full, motion_threshold) {
+                    (frame.scaled(THUMBNAIL_SIZE.0, THUMBNAIL_SIZE.1), true)
+                } else {
+                    (full.clone(), false)
+                }
+            }
+            _ => return Some(self.send_full(frame)),
+        };
+        if last_sent.is_none_or(|last| candidate.differs_from(last, motion_threshold)) {
+            self.still = 0;
+            return Some(candidate);
+        }
+        if !is_thumbnail {
+            return None;
+        }
+        // A picture that settled away from the last full frame never gets back to it by itself
+        self.still += 1;
+        if self.still < SETTLE_FRAMES {
+            return None;
+        }
+        Some(self.send_full(frame))
+    }
+
+    fn send_full(&mut self, frame: Frame) -> Frame {
+        self.full_requested = false;
+        self.still = 0;
+        self.last_full = Some(frame.clone());
+        frame
+    }
+}
+
+// Whose video is on screen. Frames from anyone else are parked, so feeds never interleave,
+// and a parked feed takes over once the shown peer leaves
+#[derive(Default)]
+struct FeedSwitch {
+    shown: Option<NodeId>,
+    parked: HashMap<NodeId, Frame>,
+}
+
+impl FeedSwitch {
+    // Hands the frame back when it belongs on screen, the first sender gets the screen
+    fn offer(&mut self, from: NodeId, frame: Frame) -> Option<Frame> {
+        if *self.shown.get_or_insert(from) == from {
+            return Some(frame);
+        }
+        self.parked.insert(from, frame);
+        None
+    }
+
+    // The shown peer left. Returns the frame of the parked peer that now has the screen, if any
+    fn shown_left(&mut self) -> Option<Frame> {
+        if let Some(peer) = self.shown.take() {
+            self.parked.remove(&peer);
+        }
+        let next = *self.parked.keys().next()?;
+        self.shown = Some(next);
+        self.parked.remove(&next)
+    }
+
+    // Returns whether the peer was the one on screen
+    fn forget(&mut self, peer: NodeId) -> bool {
+        self.parked.remove(&peer);
+        if self.shown == Some(peer) {
+            self.shown = None;
+            return true;
+        }
+        false
+    }
+
+    fn known(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.shown.iter().chain(self.parked.keys()).copied()
+    }
+}
+
+fn show_placeholder(display: &mut Option<TerminalDisplay>, placeholder: Option<Placeholder>) {
+    if let Some(disp) = display {
+        if let Err(e) = disp.set_placeholder(placeholder) {
+            eprintln!("Display error: {}", e);
+        }
+    }
+}
+
+fn show_overlay(display: &mut Option<TerminalDisplay>, lines: Option<Vec<String>>) {
+    if let Some(disp) = display {
+        if let Err(e) = disp.set_overlay(lines) {
+            eprintln!("Display error: {}", e);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum VideoCommand {
+    TogglePause,
+    ToggleStats,
+    Mute(String),
+    Unmute(String),
+    Kick(String),
+    Help,
+    // Any other line, a bare enter included; it only closes the help overlay
+    Dismiss,
+    Quit,
+}
+
+impl VideoCommand {
+    fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_whitespace();
+        let command = match (words.next()?, words.next()) {
+            ("p", None) => VideoCommand::TogglePause,
+            ("h", None) => VideoCommand::ToggleStats,
+            ("?", None) => VideoCommand::Help,
+            ("q", None) => VideoCommand::Quit,
+            ("mute", Some(peer)) => VideoCommand::Mute(peer.to_string()),
+            ("unmute", Some(peer)) => VideoCommand::Unmute(peer.to_string()),
+            ("kick", Some(peer)) => VideoCommand::Kick(peer.to_string()),
+            _ => return None,
+        };
+        Some(command)
+    }
+}
+
+// Matches the short ids printed in join messages against the peers we have seen
+fn resolve_peer(prefix: &str, peers: impl Iterator<Item = NodeId>) -> Result<NodeId, String> {
+    let mut matches: Vec<NodeId> = peers.filter(|peer| peer.to_string().starts_with(prefix)).collect();
+    matches.sort();
+    matches.dedup();
+    match matches.as_slice() {
+        [peer] => Ok(*peer),
+        [] => Err(format!("no peer matching {}", prefix)),
+        _ => Err(format!("{} matches more than one peer, type more of the id", prefix)),
+    }
+}
+
+// Line-mode so it never switches the terminal into raw mode under the video output
+fn video_input_loop(command_tx: tokio::sync::mpsc::UnboundedSender<VideoCommand>) -> Result<()> {
+    let mut buffer = String::new();
+    loop {
+        buffer.clear();
+        if std::io::stdin().read_line(&mut buffer)? == 0 {
+            return Ok(());
+        }
+        command_tx.send(VideoCommand::parse(&buffer).unwrap_or(VideoCommand::Dismiss))?;
+    }
+}
+
+async fn ping(endpoint: &Endpoint, ticket: &str, prefer: AddrFamily) -> Result<()> {
+    let ticket = Ticket::from_code_or_full(ticket)?;
+    if ticket.nodes.is_empty() {
+        return Err(anyhow::anyhow!("Invalid ticket: no nodes found"));
+    }
+
+    let mut reachable = false;
+    for node in &ticket.nodes {
+        let addr = node.to_node_addr(prefer);
+        println!("> pinging {}...", short_id(&node.node_id));
+
+        let started = std::time::Instant::now();
+        let attempt = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            endpoint.connect(addr, GOSSIP_ALPN),
+        ).await;
+        match attempt {
+            Ok(Ok(conn)) => {
+                let elapsed = started.elapsed();
+                let path = match endpoint.conn_type(node.node_id).map(|mut w| w.get()) {
+                    Some(ConnectionType::Direct(addr)) => format!("direct ({})", addr),
+                    Some(ConnectionType::Relay(url)) => format!("relayed via {}", url),
+                    Some(ConnectionType::Mixed(addr, url)) => format!("direct ({}) with relay {}", addr, url),
+                    _ => "unknown".to_string(),
+                };
+                println!("> reachable: connected in {:?}, rtt {:?}, path {}", elapsed, conn.rtt(), path);
+                conn.close(0u32.into(), b"ping");
+                reachable = true;
+            }
+            Ok(Err(e)) => println!("> unreachable: {}", e),
+            Err(_) => println!("> unreachable: timed out after 10s"),
+        }
+    }
+
+    if reachable {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("could not reach any node in the ticket")).context(Failure::ConnectFailed)
+    }
+}
+
+fn spawn_keepalive(sender: GossipSender, my_node_id: NodeId, interval_secs: u64) {
+    if interval_secs == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+            let _ = sender.broadcast(Message::new(MessageBody::KeepAlive {
+                from: my_node_id,
+            }).to_vec().into()).await;
+        }
+    });
+}
+
+// Elapsed-time indicator on its own line; clears itself when dropped so later output starts clean
+struct Spinner {
+    task: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl Spinner {
+    fn start() -> Self {
+        use std::io::{IsTerminal, Write};
+
+        // Carriage-return animation would just litter a log file
+        if !std::io::stdout().is_terminal() {
+            return Self { task: None };
+        }
+        let started = std::time::Instant::now();
+        let task = tokio::spawn(async move {
+            const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+            let mut interval = tokio::time::interval(std::time::Duration::from_millis(120));
+            for frame in FRAMES.iter().cycle() {
+                interval.tick().await;
+                print!("\r> {} {}s", frame, started.elapsed().as_secs());
+                let _ = std::io::stdout().flush();
+            }
+        });
+        Self { task: Some(task) }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        use std::io::Write;
+
+        if let Some(task) = self.task.take() {
+            task.abort();
+            print!("\r\x1B[2K");
+            let _ = std::io::stdout().flush();
+        }
+    }
+}
+
+// Pixels our terminal can show: one per column, two per row thanks to half blocks
+fn view_size(fixed_size: Option<(usize, usize)>) -> (u32, u32) {
+    let (cols, rows) = fixed_size.unwrap_or_else(display::term_size);
+    (cols as u32, (rows as u32).saturating_mul(2))
+}
+
+// Runs a receive loop until it finishes on its own or `shutdown` flips (or its sender is dropped)
+fn spawn_until_shutdown(
+    task: impl std::future::Future<Output = Result<()>> + Send + 'static,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+) -> tokio::task::JoinHandle<Result<()>> {
+    tokio::spawn(async move {
+        tokio::select! {
+            result = task => result,
+            _ = shutdown.wait_for(|stop| *stop) => Ok(()),
+        }
+    })
+}
+
+// Exit codes wrapper scripts can tell apart; any other error exits with 1
+const EXIT_ROOM_FULL: i32 = 2;
+const EXIT_CONNECT_FAILED: i32 = 3;
+// Only with --require-camera, otherwise a missing camera falls back to text chat
+const EXIT_CAMERA_UNAVAILABLE: i32 = 4;
+// --idle-timeout: nobody joined in time, or the peer that did stopped sending anything
+const EXIT_TIMED_OUT: i32 = 5;
+const EXIT_PEER_SILENT: i32 = 8;
+const EXIT_KICKED: i32 = 6;
+const EXIT_ROOM_CLOSED: i32 = 7;
+const EXIT_WRONG_PASSWORD: i32 = 9;
+
+fn exit_code(error: &anyhow::Error) -> i32 {
+    match error.downcast_ref::<Failure>() {
+        Some(Failure::RoomFull) => EXIT_ROOM_FULL,
+        Some(Failure::ConnectFailed) => EXIT_CONNECT_FAILED,
+        Some(Failure::CameraUnavailable) => EXIT_CAMERA_UNAVAILABLE,
+        Some(Failure::WrongPassword) => EXIT_WRONG_PASSWORD,
+        Some(Failure::Kicked) => EXIT_KICKED,
+        Some(Failure::RoomClosed) => EXIT_ROOM_CLOSED,
+        Some(Failure::TimedOut) => EXIT_TIMED_OUT,
+        Some(Failure::PeerSilent) => EXIT_PEER_SILENT,
+        None => 1,
+    }
+}
+
+// Lets joined peers exit instead of waiting on a host that is gone
+async fn close_room(sender: &GossipSender, my_id: NodeId) {
+    let _ = sender.broadcast(Message::new(MessageBody::RoomClosed { from: my_id }).to_vec().into()).await;
+    // Give gossip a moment to push it out before the endpoint goes away
+    tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+}
+
+// Resolves with the error the session ends on once the room sat idle for `timeout_secs`, and
+// never with the timeout off. Selected on by the session loops so the room still gets closed
+async fn idle_timeout(activity: Activity, timeout_secs: u64) -> anyhow::Error {
+    if timeout_secs == 0 {
+        return std::future::pending().await;
+    }
+    let timeout = std::time::Duration::from_secs(timeout_secs);
+    let started = std::time::Instant::now();
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        match activity.last() {
+            None if started.elapsed() >= timeout => {
+                return anyhow::anyhow!("no peer joined within {}s, closing the room", timeout_secs)
+                    .context(Failure::TimedOut);
+            }
+            Some(last) if last.elapsed() >= timeout => {
+                return anyhow::anyhow!("peer went silent for {}s, closing the room", timeout_secs)
+                    .context(Failure::PeerSilent);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Sends without blocking the capture loop; the flag lets callers drop frames while one is still going out
+fn broadcast_frame(
+    sender: &GossipSender,
+    in_flight: &Arc<AtomicBool>,
+    message_bytes: Vec<u8>,
+    errors: &tokio::sync::mpsc::UnboundedSender<String>,
+) {
+    in_flight.store(true, Ordering::Release);
+    let sender = sender.clone();
+    let in_flight = in_flight.clone();
+    let errors = errors.clone();
+    tokio::spawn(async move {
+        if let Err(e) = sender.broadcast(message_bytes.into()).await {
+            let _ = errors.send(e.to_string());
+        }
+        in_flight.store(false, Ordering::Release);
+    });
+}
+
+async fn send_presence(sender: &GossipSender, my_id: NodeId, state: PresenceState) {
+    let _ = sender.broadcast(Message::new(MessageBody::Presence {
+        from: my_id,
+        state,
+    }).to_vec().into()).await;
+}
+
+// Anyone watching the topic can replay a proof, so this only keeps out people who were
+// handed the ticket without the password
+fn password_proof(password: &str, topic: &TopicId) -> [u8; 32] {
+    let key = blake3::derive_key("p2p-video-chat room password v1", password.as_bytes());
+    *blake3::keyed_hash(&key, topic.as_bytes()).as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(seed: u8) -> NodeId {
+        SecretKey::from_bytes(&[seed; 32]).public()
+    }
+
+    fn pixel(value: u8) -> Frame {
+        Frame::new(vec![value; 3], 1, 1).unwrap()
+    }
+
+    #[test]
+    fn feed_switch_hands_the_screen_to_a_parked_peer() {
+        let mut feed = FeedSwitch::default();
+        assert_eq!(feed.offer(node(1), pixel(1)), Some(pixel(1)));
+        assert_eq!(feed.offer(node(2), pixel(2)), None);
+
+        assert_eq!(feed.shown_left(), Some(pixel(2)));
+        assert_eq!(feed.shown, Some(node(2)));
+        assert_eq!(feed.offer(node(2), pixel(3)), Some(pixel(3)));
+    }
+
+    #[test]
+    fn feed_switch_takes_the_next_sender_once_the_shown_peer_left() {
+        let mut feed = FeedSwitch::default();
+        feed.offer(node(1), pixel(1));
+        assert_eq!(feed.shown_left(), None);
+        assert_eq!(feed.shown, None);
+        assert_eq!(feed.offer(node(2), pixel(2)), Some(pixel(2)));
+    }
+
+    #[test]
+    fn feed_switch_forgets_kicked_peers() {
+        let mut feed = FeedSwitch::default();
+        feed.offer(node(1), pixel(1));
+        feed.offer(node(2), pixel(2));
+        assert!(!feed.forget(node(2)));
+        assert!(feed.forget(node(1)));
+        assert_eq!(feed.known().count(), 0);
+    }
+
+    // 320x240 in one color, with the top `rows` rows in another
+    fn banded(rows: u32, value: u8) -> Frame {
+        let mut data = vec![0; 320 * 240 * 3];
+        data[..(rows * 320 * 3) as usize].fill(value);
+        Frame::new(data, 320, 240).unwrap()
+    }
+
+    #[test]
+    fn thumbnails_go_back_to_full_resolution_once_the_picture_settles() {
+        let mut gate = ThumbnailGate::default();
+        let first = banded(0, 0);
+        assert_eq!(gate.next(first.clone(), None, 5), Some(first.clone()));
+
+        // A tenth of the picture changed: enough for motion, not enough for a new full frame
+        let moved = banded(24, 200);
+        let thumbnail = gate.next(moved.clone(), Some(&first), 5).unwrap();
+        assert!(thumbnail.width <= THUMBNAIL_SIZE.0 && thumbnail.height <= THUMBNAIL_SIZE.1);
+
+        for _ in 1..SETTLE_FRAMES {
+            assert_eq!(gate.next(moved.clone(), Some(&thumbnail), 5), None);
+        }
+        assert_eq!(gate.next(moved.clone(), Some(&thumbnail), 5), Some(moved.clone()));
+        // That is the new full frame, so the settled picture stays quiet from now on
+        for _ in 0..SETTLE_FRAMES * 2 {
+            assert_eq!(gate.next(moved.clone(), Some(&moved), 5), None);
+        }
+    }
+
+    #[test]
+    fn thumbnails_send_full_resolution_on_request_and_on_big_changes() {
+        let mut gate = ThumbnailGate::default();
+        let first = banded(0, 0);
+        gate.next(first.clone(), None, 5);
+
+        gate.request_full();
+        assert_eq!(gate.next(first.clone(), Some(&first), 5), Some(first.clone()));
+        assert_eq!(gate.next(first.clone(), Some(&first), 5), None);
+
+        let changed = banded(120, 200);
+        assert_eq!(gate.next(changed.clone(), Some(&first), 5), Some(changed));
+    }
+
+    fn sample_ticket() -> Ticket {
+        Ticket {
+            topic: TopicId::from_bytes([7; 32]),
+            nodes: vec![
+                CompactNodeInfo {
+                    node_id: node(1),
+                    relay_url: Some("https://relay.example.com".parse().unwrap()),
+                    direct_addresses: vec!["192.0.2.1:4433".parse().unwrap(), "[2001:db8::1]:4433".parse().unwrap()],
+                },
+                CompactNodeInfo {
+                    node_id: node(2),
+                    relay_url: None,
+                    direct_addresses: vec!["[::1]:9000".parse().unwrap(), "[2001:db8:0:1::2]:65535".parse().unwrap()],
+                },
+            ],
+            host_name: Some("ana".to_string()),
+        }
+    }
+
+    fn legacy_sample() -> LegacyTicket {
+        let ticket = sample_ticket();
+        LegacyTicket {
+            topic: ticket.topic,
+            nodes: ticket
+                .nodes
+                .into_iter()
+                .map(|node| LegacyNodeInfo {
+                    node_id: node.node_id,
+                    direct_addresses: node.direct_addresses,
+                })
+                .collect(),
+        }
+    }
+
+    fn with_header(magic: &[u8], version: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = magic.to_vec();
+        bytes.push(version);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn ticket_round_trips_through_bytes() {
+        let ticket = sample_ticket();
+        assert_eq!(Ticket::from_bytes(&ticket.to_bytes()).unwrap(), ticket);
+    }
+
+    #[test]
+    fn ticket_round_trips_deflated() {
+        let ticket = sample_ticket();
+        let bytes = ticket.to_compressed_bytes();
+        assert!(bytes.starts_with(TICKET_MAGIC_DEFLATE));
+        assert_eq!(Ticket::from_bytes(&bytes).unwrap(), ticket);
+    }
+
+    #[test]
+    fn ticket_round_trips_through_base64() {
+        let ticket = sample_ticket();
+        let encoded = ticket.to_string();
+        // Has to survive being pasted into a URL or a shell unquoted
+        assert!(encoded.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(encoded.parse::<Ticket>().unwrap(), ticket);
+    }
+
+    #[test]
+    fn ticket_round_trips_through_the_saved_rooms_format() {
+        let mut registry = TicketRegistry { tickets: HashMap::new(), path: PathBuf::new() };
+        registry.tickets.insert("abcd1234".to_string(), sample_ticket());
+        let saved = serde_json::to_string_pretty(&registry).unwrap();
+        let loaded: TicketRegistry = serde_json::from_str(&saved).unwrap();
+        assert_eq!(loaded.get_ticket("abcd1234"), Some(&sample_ticket()));
+    }
+
+    #[test]
+    fn unversioned_tickets_decode_without_relays() {
+        let bytes = postcard::to_allocvec(&legacy_sample()).unwrap();
+        let ticket = Ticket::from_bytes(&bytes).unwrap();
+        assert_eq!(ticket.topic, sample_ticket().topic);
+        assert_eq!(ticket.nodes[1].direct_addresses, sample_ticket().nodes[1].direct_addresses);
+        assert!(ticket.nodes.iter().all(|node| node.relay_url.is_none()));
+        assert_eq!(ticket.host_name, None);
+    }
+
+    #[test]
+    fn v1_tickets_decode_without_relays() {
+        let payload = postcard::to_allocvec(&legacy_sample()).unwrap();
+        let ticket = Ticket::from_bytes(&with_header(TICKET_MAGIC, 1, &payload)).unwrap();
+        assert_eq!(ticket.nodes[0].direct_addresses, sample_ticket().nodes[0].direct_addresses);
+        assert_eq!(ticket.nodes[0].relay_url, None);
+    }
+
+    #[test]
+    fn v2_tickets_keep_the_relay_but_have_no_host() {
+        let sample = sample_ticket();
+        let v2 = TicketV2 {
+            topic: sample.topic,
+            nodes: sample.nodes.clone(),
+        };
+        let payload = postcard::to_allocvec(&v2).unwrap();
+        let ticket = Ticket::from_bytes(&with_header(TICKET_MAGIC, 2, &payload)).unwrap();
+        assert_eq!(ticket.nodes, sample.nodes);
+        assert_eq!(ticket.host_name, None);
+    }
+
+    #[test]
+    fn corrupt_tickets_are_errors() {
+        assert!(Ticket::from_bytes(&with_header(TICKET_MAGIC, TICKET_VERSION, &[0xff; 5])).is_err());
+        assert!(Ticket::from_bytes(&with_header(TICKET_MAGIC_DEFLATE, TICKET_VERSION, &[0xff; 5])).is_err());
+        assert!(Ticket::from_bytes(TICKET_MAGIC).is_err());
+        assert!("not a ticket!".parse::<Ticket>().is_err());
+    }
+
+    #[test]
+    fn wrong_magic_is_an_error() {
+        let mut bytes = sample_ticket().to_bytes();
+        bytes[..4].copy_from_slice(b"nope");
+        assert!(Ticket::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn newer_ticket_versions_ask_for_an_update() {
+        let payload = postcard::to_allocvec(&sample_ticket()).unwrap();
+        let err = Ticket::from_bytes(&with_header(TICKET_MAGIC, TICKET_VERSION + 1, &payload)).unwrap_err();
+        assert!(err.to_string().contains("newer version"));
+    }
+
+    // A fresh directory per test, so registry tests never see each other's files or the real one
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("p2p-video-chat-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn short_codes_are_short_lowercase_alphanumerics() {
+        assert!(looks_like_short_code("a1b2c3d4"));
+        assert!(!looks_like_short_code(""));
+        assert!(!looks_like_short_code("A1B2C3D4"));
+        assert!(!looks_like_short_code("a1b2c3d4e"));
+        assert!(!looks_like_short_code(&sample_ticket().to_string()));
+    }
+
+    #[test]
+    fn truncated_tickets_are_errors() {
+        let full = sample_ticket().to_string();
+        let truncated = &full[..full.len() / 2];
+        assert!(Ticket::from_code_or_full_in(truncated, || unreachable!()).is_err());
+    }
+
+    #[test]
+    fn tickets_with_a_wrong_magic_are_errors() {
+        let mut bytes = sample_ticket().to_bytes();
+        bytes[..4].copy_from_slice(b"nope");
+        let encoded = data_encoding::BASE64URL_NOPAD.encode(&bytes);
+        assert!(Ticket::from_code_or_full_in(&encoded, || unreachable!()).is_err());
+    }
+
+    #[test]
+    fn unknown_short_codes_are_errors() {
+        let dir = scratch_dir("unknown-code");
+        let err = Ticket::from_code_or_full_in("zzzz9999", || TicketRegistry::load_from(dir.join("tickets.json")))
+            .unwrap_err();
+        assert!(err.to_string().contains("no saved room"));
+    }
+
+    #[test]
+    fn padding_around_codes_and_tickets_is_ignored() {
+        let ticket = sample_ticket();
+        let padded = format!("  \n{}\r\n\t", ticket);
+        assert_eq!(Ticket::from_code_or_full_in(&padded, || unreachable!()).unwrap(), ticket);
+
+        let dir = scratch_dir("padded-code");
+        let mut registry = TicketRegistry::load_from(dir.join("tickets.json"));
+        let code = registry.register_ticket(ticket.clone()).unwrap();
+        let found = Ticket::from_code_or_full_in(&format!(" {}\n", code), || {
+            TicketRegistry::load_from(dir.join("tickets.json"))
+        });
+        assert_eq!(found.unwrap(), ticket);
+    }
+
+    fn ticket_for_topic(seed: u8) -> Ticket {
+        Ticket {
+            topic: TopicId::from_bytes([seed; 32]),
+            ..sample_ticket()
+        }
+    }
+
+    #[test]
+    fn concurrent_registries_keep_each_others_codes() {
+        let path = scratch_dir("concurrent").join("tickets.json");
+        let writers: Vec<_> = [0u8, 100]
+            .into_iter()
+            .map(|base| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let mut registry = TicketRegistry::load_from(path);
+                    (0..10)
+                        .map(|i| registry.register_ticket(ticket_for_topic(base + i)).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let codes: Vec<String> = writers.into_iter().flat_map(|writer| writer.join().unwrap()).collect();
+
+        let saved = TicketRegistry::load_from(path);
+        assert_eq!(saved.tickets.len(), 20);
+        for code in codes {
+            assert!(saved.get_ticket(&code).is_some(), "code {} was lost", code);
+        }
+    }
+
+    #[test]
+    fn corrupt_registry_is_backed_up_and_starts_empty() {
+        let path = scratch_dir("corrupt").join("tickets.json");
+        fs::write(&path, "{ not json").unwrap();
+
+        let registry = TicketRegistry::load_from(path.clone());
+        assert!(registry.tickets.is_empty());
+        assert_eq!(fs::read_to_string(path.with_extension("json.bak")).unwrap(), "{ not json");
+        assert!(!path.exists());
+    }
+
+    fn mixed_addrs() -> Vec<std::net::SocketAddr> {
+        ["192.0.2.1:4433", "[2001:db8::1]:4433", "198.51.100.7:4433", "[fe80::2]:4433"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ipv6_only_drops_every_ipv4_address() {
+        let kept = AddrFamily::Ipv6.filter(&mixed_addrs());
+        assert_eq!(kept, vec![mixed_addrs()[1], mixed_addrs()[3]]);
+
+        let v4_only: Vec<std::net::SocketAddr> = vec!["192.0.2.1:4433".parse().unwrap()];
+        assert!(AddrFamily::Ipv6.filter(&v4_only).is_empty());
+    }
+
+    #[test]
+    fn ipv4_only_drops_every_ipv6_address() {
+        let kept = AddrFamily::Ipv4.filter(&mixed_addrs());
+        assert_eq!(kept, vec![mixed_addrs()[0], mixed_addrs()[2]]);
+    }
+
+    #[test]
+    fn dual_stack_keeps_everything_in_the_original_order() {
+        assert_eq!(AddrFamily::Both.filter(&mixed_addrs()), mixed_addrs());
+        assert!(AddrFamily::Both.filter(&[]).is_empty());
+    }
+
+    #[test]
+    fn node_addr_only_carries_the_preferred_family() {
+        let info = CompactNodeInfo {
+            node_id: node(3),
+            relay_url: Some("https://relay.example.com".parse().unwrap()),
+            direct_addresses: mixed_addrs(),
+        };
+
+        let addr = info.to_node_addr(AddrFamily::Ipv6);
+        assert_eq!(addr.node_id, info.node_id);
+        assert_eq!(addr.relay_url, info.relay_url);
+        assert!(addr.direct_addresses.iter().all(|addr| addr.is_ipv6()));
+        assert_eq!(addr.direct_addresses.len(), 2);
+
+        let addr = info.to_node_addr(AddrFamily::Both);
+        assert_eq!(addr.direct_addresses.len(), 4);
+
+        // A v4-only node seen from an IPv6-only network is left with its relay
+        let v4_node = CompactNodeInfo { direct_addresses: vec!["192.0.2.1:4433".parse().unwrap()], ..info };
+        let addr = v4_node.to_node_addr(AddrFamily::Ipv6);
+        assert!(addr.direct_addresses.is_empty());
+        assert!(addr.relay_url.is_some());
+    }
+}
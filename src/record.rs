@@ -0,0 +1,188 @@
+use anyhow::{anyhow, Context, Result};
+use p2p_video_chat::codec::Frame;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// A recording is a directory: one JSON line per frame in the manifest, pixels back to back in
+// the frames file, so a long session never has to fit in memory
+const MANIFEST: &str = "manifest.jsonl";
+const FRAMES: &str = "frames.rgb";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameEntry {
+    // Since the recording started
+    pub at_ms: u64,
+    pub width: u32,
+    pub height: u32,
+    // Where this frame's RGB data starts in the frames file
+    pub offset: u64,
+}
+
+// Saves the frames shown from the peer, with --record
+pub struct Recorder {
+    manifest: BufWriter<File>,
+    frames: BufWriter<File>,
+    offset: u64,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn create(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create recording directory {}", dir.display()))?;
+        let create = |name: &str| {
+            File::create(dir.join(name)).with_context(|| format!("failed to create {}", dir.join(name).display()))
+        };
+        Ok(Self {
+            manifest: BufWriter::new(create(MANIFEST)?),
+            frames: BufWriter::new(create(FRAMES)?),
+            offset: 0,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, frame: &Frame) -> Result<()> {
+        let entry = FrameEntry {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            width: frame.width,
+            height: frame.height,
+            offset: self.offset,
+        };
+        self.frames.write_all(&frame.data)?;
+        // Pixels first, so a manifest line never points past the end of the frames file
+        self.frames.flush()?;
+        serde_json::to_writer(&mut self.manifest, &entry)?;
+        self.manifest.write_all(b"\n")?;
+        self.manifest.flush()?;
+        self.offset += frame.data.len() as u64;
+        Ok(())
+    }
+}
+
+// A recording opened for `replay`; frames are read from disk as they are played
+pub struct Recording {
+    pub entries: Vec<FrameEntry>,
+    frames: File,
+}
+
+impl Recording {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let manifest = File::open(dir.join(MANIFEST))
+            .with_context(|| format!("{} is not a recording (no {} inside)", dir.display(), MANIFEST))?;
+        let lines = BufReader::new(manifest).lines().collect::<std::io::Result<Vec<_>>>()?;
+        let mut entries = Vec::with_capacity(lines.len());
+        for (number, line) in lines.iter().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                // A session that was killed mid-write leaves half a line at the end
+                Err(_) if number + 1 == lines.len() => break,
+                Err(e) => return Err(anyhow!("{} line {} is corrupt: {}", MANIFEST, number + 1, e)),
+            }
+        }
+        let frames = File::open(dir.join(FRAMES))
+            .with_context(|| format!("{} is not a recording (no {} inside)", dir.display(), FRAMES))?;
+        Ok(Self { entries, frames })
+    }
+
+    pub fn frame(&mut self, entry: &FrameEntry) -> Result<Frame> {
+        let len = (entry.width as usize)
+            .checked_mul(entry.height as usize)
+            .and_then(|pixels| pixels.checked_mul(3))
+            .ok_or_else(|| anyhow!("recorded frame size {}x{} is invalid", entry.width, entry.height))?;
+        let mut data = vec![0; len];
+        self.frames.seek(SeekFrom::Start(entry.offset))?;
+        self.frames.read_exact(&mut data).context("recording is truncated")?;
+        Frame::new(data, entry.width, entry.height)
+    }
+}
+
+// When to show each entry, as an offset from the start of playback. `speed` scales the recorded
+// gaps, and frames closer than `min_gap` to the last one kept are dropped like a live capture
+// at --interval-ms would
+pub fn schedule(entries: &[FrameEntry], speed: f32, min_gap: Duration) -> Vec<(Duration, usize)> {
+    let mut kept: Vec<(Duration, usize)> = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let at = Duration::from_micros((entry.at_ms as f64 * 1000.0 / speed as f64).round() as u64);
+        if kept.last().is_some_and(|(last, _)| at.saturating_sub(*last) < min_gap) {
+            continue;
+        }
+        kept.push((at, index));
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("p2p-record-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entry(at_ms: u64) -> FrameEntry {
+        FrameEntry { at_ms, width: 1, height: 1, offset: 0 }
+    }
+
+    #[test]
+    fn recorded_frames_play_back_unchanged() {
+        let dir = scratch_dir("round-trip");
+        let frames = [
+            Frame::new(vec![1, 2, 3], 1, 1).unwrap(),
+            Frame::new((0..12).collect(), 2, 2).unwrap(),
+        ];
+        let mut recorder = Recorder::create(&dir).unwrap();
+        for frame in &frames {
+            recorder.record(frame).unwrap();
+        }
+        drop(recorder);
+
+        let mut recording = Recording::open(&dir).unwrap();
+        assert_eq!(recording.entries.len(), 2);
+        assert!(recording.entries[0].at_ms <= recording.entries[1].at_ms);
+        for (entry, frame) in recording.entries.clone().iter().zip(&frames) {
+            assert_eq!(&recording.frame(entry).unwrap(), frame);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_half_written_last_line_is_ignored() {
+        let dir = scratch_dir("truncated");
+        let mut recorder = Recorder::create(&dir).unwrap();
+        recorder.record(&Frame::new(vec![9, 9, 9], 1, 1).unwrap()).unwrap();
+        drop(recorder);
+        let mut manifest = fs::OpenOptions::new().append(true).open(dir.join(MANIFEST)).unwrap();
+        manifest.write_all(b"{\"at_ms\":40,\"wid").unwrap();
+
+        assert_eq!(Recording::open(&dir).unwrap().entries.len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_directory_without_a_manifest_is_not_a_recording() {
+        let dir = scratch_dir("empty");
+        fs::create_dir_all(&dir).unwrap();
+        assert!(Recording::open(&dir).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn schedule_scales_by_speed_and_drops_frames_closer_than_the_interval() {
+        let entries = [entry(0), entry(100), entry(110), entry(300)];
+
+        let normal = schedule(&entries, 1.0, Duration::from_millis(33));
+        assert_eq!(normal, vec![
+            (Duration::ZERO, 0),
+            (Duration::from_millis(100), 1),
+            (Duration::from_millis(300), 3),
+        ]);
+
+        let doubled = schedule(&entries, 2.0, Duration::from_millis(1));
+        assert_eq!(doubled.len(), 4);
+        assert_eq!(doubled[3].0, Duration::from_millis(150));
+    }
+}
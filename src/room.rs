@@ -0,0 +1,585 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{Context, Result};
+use futures_lite::StreamExt;
+use iroh::{Endpoint, NodeAddr, NodeId};
+use iroh_gossip::api::{Event, GossipReceiver, GossipSender};
+use serde::{Deserialize, Serialize};
+
+use crate::codec::{Capabilities, Frame};
+use crate::events::{AppEvent, EventSink};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub body: MessageBody,
+    pub nonce: [u8; 16],
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MessageBody {
+    // `view` is how many pixels the sender's terminal can show, `None` for chat-only peers
+    AboutMe {
+        from: NodeId,
+        proof: Option<[u8; 32]>,
+        view: Option<(u32, u32)>,
+        caps: Capabilities,
+        nick: Option<String>,
+    },
+    // `checksum` is `Frame::checksum` of the frame as sent, absent if the sender skipped it
+    VideoFrame { from: NodeId, frame: Frame, checksum: Option<[u8; 32]> },
+    RoomFull { from: NodeId, target: NodeId },
+    KeepAlive { from: NodeId },
+    Message { from: NodeId, text: String },
+    Ack { from: NodeId, nonce: [u8; 16] },
+    AuthFailed { from: NodeId, target: NodeId },
+    RoomInfo { from: NodeId, title: String },
+    RoomClosed { from: NodeId },
+    Kicked { from: NodeId, target: NodeId },
+    ViewSize { from: NodeId, width: u32, height: u32 },
+    Presence { from: NodeId, state: PresenceState },
+    // Asks a --thumbnails sender for one frame at full resolution
+    RequestFullFrame { from: NodeId, target: NodeId },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PresenceState {
+    Active,
+    Away,
+    CameraOff,
+}
+
+impl MessageBody {
+    pub fn sender(&self) -> NodeId {
+        match self {
+            MessageBody::AboutMe { from, .. }
+            | MessageBody::VideoFrame { from, .. }
+            | MessageBody::RoomFull { from, .. }
+            | MessageBody::KeepAlive { from }
+            | MessageBody::Message { from, .. }
+            | MessageBody::Ack { from, .. }
+            | MessageBody::AuthFailed { from, .. }
+            | MessageBody::RoomInfo { from, .. }
+            | MessageBody::RoomClosed { from }
+            | MessageBody::Kicked { from, .. }
+            | MessageBody::ViewSize { from, .. }
+            | MessageBody::Presence { from, .. }
+            | MessageBody::RequestFullFrame { from, .. } => *from,
+        }
+    }
+}
+
+impl PresenceState {
+    pub fn describe(self) -> &'static str {
+        match self {
+            PresenceState::Active => "is back",
+            PresenceState::Away => "is away",
+            PresenceState::CameraOff => "turned their camera off",
+        }
+    }
+}
+
+// Counting ourselves
+pub const ROOM_CAPACITY: usize = 2;
+
+// Set from --id-len before anything is printed, 0 keeps iroh's short form
+pub static ID_LEN: AtomicUsize = AtomicUsize::new(0);
+
+pub fn short_id(id: &NodeId) -> String {
+    match ID_LEN.load(Ordering::Relaxed) {
+        0 => id.fmt_short().to_string(),
+        len => id.to_string().chars().take(len).collect(),
+    }
+}
+
+const PROTOCOL_VERSION: u8 = 3;
+// Gossip drops anything bigger, so senders check against it before broadcasting
+pub const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
+impl Message {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        match bytes.split_first() {
+            Some((&PROTOCOL_VERSION, payload)) => postcard::from_bytes(payload).map_err(Into::into),
+            Some((&version, _)) => Err(anyhow::anyhow!(
+                "peer uses protocol v{} but this app speaks v{}, both sides need the same version",
+                version,
+                PROTOCOL_VERSION
+            )),
+            None => Err(anyhow::anyhow!("empty message")),
+        }
+    }
+
+    pub fn new(body: MessageBody) -> Self {
+        Self {
+            body,
+            nonce: rand::random(),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = vec![PROTOCOL_VERSION];
+        bytes.extend(postcard::to_allocvec(self).expect("Serialization should never fail"));
+        bytes
+    }
+}
+
+// Attached as context to errors that should end the process with their own exit code
+#[derive(Debug, Clone, Copy)]
+pub enum Failure {
+    RoomFull,
+    ConnectFailed,
+    CameraUnavailable,
+    WrongPassword,
+    Kicked,
+    RoomClosed,
+    TimedOut,
+    PeerSilent,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::RoomFull => write!(f, "room is full"),
+            Failure::ConnectFailed => write!(f, "connection failed"),
+            Failure::CameraUnavailable => write!(f, "camera unavailable"),
+            Failure::WrongPassword => write!(f, "wrong password"),
+            Failure::Kicked => write!(f, "removed from the room"),
+            Failure::RoomClosed => write!(f, "room closed"),
+            Failure::TimedOut => write!(f, "timed out"),
+            Failure::PeerSilent => write!(f, "peer went silent"),
+        }
+    }
+}
+
+// When the last message from any peer arrived, `None` until someone shows up
+#[derive(Clone, Default)]
+pub struct Activity(Arc<std::sync::Mutex<Option<std::time::Instant>>>);
+
+impl Activity {
+    pub fn touch(&self) {
+        *self.0.lock().unwrap() = Some(std::time::Instant::now());
+    }
+
+    pub fn last(&self) -> Option<std::time::Instant> {
+        *self.0.lock().unwrap()
+    }
+}
+
+pub struct RoomSettings {
+    pub required_proof: Option<[u8; 32]>,
+    pub title: Option<String>,
+    pub rejoin: Rejoin,
+    // Peers quiet for this long are dropped from the room, `None` with keepalives off
+    pub peer_timeout: Option<std::time::Duration>,
+}
+
+#[derive(Clone)]
+pub struct Rejoin {
+    pub endpoint: Endpoint,
+    // Empty for the host, who just waits for peers to come back
+    pub peers: Vec<NodeAddr>,
+}
+
+impl Rejoin {
+    pub async fn attempt(&self, sender: &GossipSender) -> Result<()> {
+        for addr in &self.peers {
+            self.endpoint.add_node_addr(addr.clone())?;
+        }
+        sender.join_peers(self.peers.iter().map(|addr| addr.node_id).collect()).await?;
+        Ok(())
+    }
+}
+
+// Follows gossip neighbor events so a silently dropped connection is noticed and re-dialed
+#[derive(Default)]
+pub struct Neighbors {
+    up: std::collections::HashSet<NodeId>,
+    lost: bool,
+}
+
+impl Neighbors {
+    // Returns a notice for the user when the room connection drops or comes back
+    pub async fn update(&mut self, event: &Event, rejoin: &Rejoin, sender: &GossipSender) -> Option<String> {
+        match event {
+            Event::NeighborUp(peer) => {
+                self.up.insert(*peer);
+                if std::mem::take(&mut self.lost) {
+                    return Some("reconnected to the room".to_string());
+                }
+                None
+            }
+            Event::NeighborDown(peer) => {
+                self.up.remove(peer);
+                if !self.up.is_empty() || self.lost {
+                    return None;
+                }
+                self.lost = true;
+                if rejoin.peers.is_empty() {
+                    return Some("lost connection to the peer, waiting for them to come back".to_string());
+                }
+                match rejoin.attempt(sender).await {
+                    Ok(()) => Some("lost connection to the room, trying to reconnect...".to_string()),
+                    Err(e) => Some(format!("lost connection to the room and could not reconnect: {}", e)),
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct VideoChannels {
+    pub frames: tokio::sync::mpsc::UnboundedSender<(NodeId, Frame)>,
+    pub captions: tokio::sync::mpsc::UnboundedSender<String>,
+    pub kicks: tokio::sync::mpsc::UnboundedReceiver<NodeId>,
+    pub views: tokio::sync::mpsc::UnboundedSender<(NodeId, u32, u32)>,
+    pub caps: tokio::sync::mpsc::UnboundedSender<(NodeId, Capabilities)>,
+    pub presence: tokio::sync::mpsc::UnboundedSender<(NodeId, PresenceState)>,
+    pub full_frame_requests: tokio::sync::mpsc::UnboundedSender<NodeId>,
+    // People in the room counting ourselves, sent whenever it changes
+    pub occupancy: tokio::sync::mpsc::UnboundedSender<usize>,
+}
+
+pub async fn subscribe_loop(
+    mut receiver: GossipReceiver, 
+    sender: GossipSender, 
+    my_node_id: NodeId,
+    room: RoomSettings,
+    activity: Activity,
+    channels: VideoChannels,
+    events: EventSink
+) -> Result<()> {
+    let RoomSettings { required_proof, title, rejoin, peer_timeout } = room;
+    let mut neighbors = Neighbors::default();
+    let VideoChannels {
+        frames: frame_tx,
+        captions: caption_tx,
+        kicks: mut kick_rx,
+        views: view_tx,
+        caps: caps_tx,
+        presence: presence_tx,
+        full_frame_requests: full_frame_tx,
+        occupancy: occupancy_tx,
+    } = channels;
+    let mut connected_peers = std::collections::HashSet::new();
+    let mut last_seen: HashMap<NodeId, std::time::Instant> = HashMap::new();
+    let mut reported_occupancy = None;
+    let mut prune = tokio::time::interval(std::time::Duration::from_secs(1));
+    let mut rejected_peers = std::collections::HashSet::new();
+    let mut room_full_sent: HashMap<NodeId, std::time::Instant> = HashMap::new();
+    let mut bad_frame_logged = false;
+    let mut corrupt_frames: u64 = 0;
+    let mut room_title: Option<String> = None;
+    
+    loop {
+        // Checked at the top so every path that admits or drops a peer gets reported
+        let occupancy = connected_peers.len() + 1;
+        if reported_occupancy != Some(occupancy) {
+            reported_occupancy = Some(occupancy);
+            let _ = occupancy_tx.send(occupancy);
+        }
+        let event = tokio::select! {
+            event = receiver.try_next() => match event? {
+                Some(event) => event,
+                None => break,
+            },
+            _ = prune.tick(), if peer_timeout.is_some() => {
+                let timeout = peer_timeout.unwrap_or_default();
+                let silent: Vec<NodeId> = connected_peers
+                    .iter()
+                    .filter(|peer| last_seen.get(*peer).is_none_or(|seen| seen.elapsed() > timeout))
+                    .copied()
+                    .collect();
+                for peer in silent {
+                    connected_peers.remove(&peer);
+                    last_seen.remove(&peer);
+                    println!("{} went silent and was dropped from the room", short_id(&peer));
+                    events.emit(AppEvent::PeerLeft { peer: peer.to_string() });
+                }
+                continue;
+            }
+            Some(peer) = kick_rx.recv() => {
+                connected_peers.remove(&peer);
+                rejected_peers.insert(peer);
+                events.emit(AppEvent::PeerRejected { peer: peer.to_string() });
+                let _ = sender.broadcast(Message::new(MessageBody::Kicked {
+                    from: my_node_id,
+                    target: peer,
+                }).to_vec().into()).await;
+                continue;
+            }
+        };
+        if let Some(notice) = neighbors.update(&event, &rejoin, &sender).await {
+            let _ = caption_tx.send(format!("> {}", notice));
+        }
+        if let Event::Received(msg) = event {
+            activity.touch();
+            match Message::from_bytes(&msg.content) {
+                Ok(message) => {
+                    last_seen.insert(message.body.sender(), std::time::Instant::now());
+                    match message.body {
+                MessageBody::AboutMe { from, proof, view, caps, .. } => {
+                    if from == my_node_id {
+                        continue;
+                    }
+                    
+                    if rejected_peers.contains(&from) {
+                        send_room_full(&sender, my_node_id, from, &mut room_full_sent).await;
+                        continue;
+                    }
+
+                    if required_proof.is_some() && proof != required_proof {
+                        println!("{} tried to join with a wrong password. Rejecting connection.", short_id(&from));
+                        events.emit(AppEvent::PeerRejected { peer: from.to_string() });
+                        let _ = sender.broadcast(Message::new(MessageBody::AuthFailed {
+                            from: my_node_id,
+                            target: from,
+                        }).to_vec().into()).await;
+                        continue;
+                    }
+                    
+                    // A frame or keepalive may have let this peer in before its AboutMe arrived
+                    if !connected_peers.contains(&from) && connected_peers.len() + 1 >= ROOM_CAPACITY {
+                        println!("{} tried to join but room is full. Rejecting connection.", short_id(&from));
+                        events.emit(AppEvent::PeerRejected { peer: from.to_string() });
+                        rejected_peers.insert(from);
+                        send_room_full(&sender, my_node_id, from, &mut room_full_sent).await;
+                    } else {
+                        admit_peer(&mut connected_peers, from, &events);
+                        if let Some((width, height)) = view {
+                            let _ = view_tx.send((from, width, height));
+                        }
+                        let _ = caps_tx.send((from, caps));
+                    }
+                    // Re-sent on every join so late joiners see it too
+                    if let Some(title) = &title {
+                        send_room_info(&sender, my_node_id, title).await;
+                    }
+                },
+                MessageBody::VideoFrame { from, frame, checksum } => {
+                    if from == my_node_id {
+                        continue;
+                    }
+                    // A failed decode on the sender can still arrive as an empty or zero-sized frame
+                    if let Err(e) = frame.validate() {
+                        if !bad_frame_logged {
+                            bad_frame_logged = true;
+                            eprintln!("Dropping bad frame from {}: {}", short_id(&from), e);
+                        }
+                        continue;
+                    }
+                    // Decoded fine but the pixels changed on the way, so it's the link, not the camera
+                    if checksum.is_some_and(|sum| sum != frame.checksum()) {
+                        corrupt_frames += 1;
+                        // Backs off so a bad link doesn't flood the terminal
+                        if corrupt_frames.is_power_of_two() {
+                            eprintln!(
+                                "Warning: dropped {} frame(s) from {} that failed the checksum (corrupted in transit)",
+                                corrupt_frames,
+                                short_id(&from)
+                            );
+                        }
+                        continue;
+                    }
+                    
+                    if rejected_peers.contains(&from) {
+                        send_room_full(&sender, my_node_id, from, &mut room_full_sent).await;
+                        continue;
+                    }
+                    
+                    if connected_peers.contains(&from) {
+                        let _ = frame_tx.send((from, frame));
+                    } else if required_proof.is_some() {
+                        // Password rooms only admit peers through a checked AboutMe
+                        continue;
+                    } else if connected_peers.len() + 1 < ROOM_CAPACITY {
+                        admit_peer(&mut connected_peers, from, &events);
+                        let _ = frame_tx.send((from, frame));
+                    } else {
+                        rejected_peers.insert(from);
+                        events.emit(AppEvent::PeerRejected { peer: from.to_string() });
+                        send_room_full(&sender, my_node_id, from, &mut room_full_sent).await;
+                    }
+                },
+                // These end the session through `run`, which restores the terminal first
+                MessageBody::RoomFull { from, target } => {
+                    if from != my_node_id && target == my_node_id {
+                        events.emit(AppEvent::RoomFull);
+                        return Err(anyhow::anyhow!("only {} people allowed per room", ROOM_CAPACITY)).context(Failure::RoomFull);
+                    }
+                },
+                MessageBody::AuthFailed { from, target } => {
+                    if from != my_node_id && target == my_node_id {
+                        events.emit(AppEvent::Error {
+                            message: "wrong password for this room".to_string(),
+                        });
+                        return Err(anyhow::anyhow!("wrong password for this room")).context(Failure::WrongPassword);
+                    }
+                },
+                MessageBody::KeepAlive { from } => {
+                    if from == my_node_id {
+                        continue;
+                    }
+                    if rejected_peers.contains(&from) {
+                        send_room_full(&sender, my_node_id, from, &mut room_full_sent).await;
+                        continue;
+                    }
+                    if connected_peers.len() + 1 < ROOM_CAPACITY && required_proof.is_none() {
+                        admit_peer(&mut connected_peers, from, &events);
+                    }
+                },
+                MessageBody::Message { from, text } => {
+                    if from == my_node_id || rejected_peers.contains(&from) {
+                        continue;
+                    }
+                    // Password rooms only take text from peers admitted through a checked AboutMe
+                    if required_proof.is_some() && !connected_peers.contains(&from) {
+                        continue;
+                    }
+                    let _ = caption_tx.send(format!("{}: {}", short_id(&from), text));
+                    let _ = sender.broadcast(Message::new(MessageBody::Ack {
+                        from: my_node_id,
+                        nonce: message.nonce,
+                    }).to_vec().into()).await;
+                },
+                MessageBody::RoomInfo { from, title } => {
+                    if from != my_node_id && room_title.as_ref() != Some(&title) {
+                        let _ = caption_tx.send(format!("> connected to: {}", title));
+                        room_title = Some(title);
+                    }
+                },
+                MessageBody::ViewSize { from, width, height } => {
+                    if connected_peers.contains(&from) {
+                        let _ = view_tx.send((from, width, height));
+                    }
+                },
+                MessageBody::Presence { from, state } => {
+                    if connected_peers.contains(&from) {
+                        let _ = presence_tx.send((from, state));
+                    }
+                },
+                MessageBody::RequestFullFrame { from, target } => {
+                    if target == my_node_id && connected_peers.contains(&from) {
+                        let _ = full_frame_tx.send(from);
+                    }
+                },
+                MessageBody::Kicked { from, target } => {
+                    if from != my_node_id && target == my_node_id {
+                        events.emit(AppEvent::Kicked);
+                        return Err(anyhow::anyhow!("the host removed you from the room")).context(Failure::Kicked);
+                    }
+                },
+                MessageBody::RoomClosed { from } => {
+                    if from != my_node_id {
+                        events.emit(AppEvent::RoomClosed);
+                        return Err(anyhow::anyhow!("host closed the room")).context(Failure::RoomClosed);
+                    }
+                },
+                MessageBody::Ack { .. } => {}
+            }
+        },
+        Err(e) => {
+            eprintln!("Failed to decode message: {}", e);
+            events.emit(AppEvent::Error {
+                message: format!("failed to decode message: {}", e),
+            });
+        }
+    }
+        }
+    }
+    Ok(())
+}
+
+// Every way into the room goes through here, so a peer is announced once whichever message
+// happened to arrive first
+fn admit_peer(connected_peers: &mut std::collections::HashSet<NodeId>, peer: NodeId, events: &EventSink) {
+    if let Some(line) = join_line(connected_peers, peer) {
+        println!("{}", line);
+        events.emit(AppEvent::PeerJoined { peer: peer.to_string() });
+    }
+}
+
+// The join announcement for a peer seen for the first time, and nothing for every later
+// message from it, so a burst of KeepAlives or frames still reads as one join
+fn join_line(connected_peers: &mut std::collections::HashSet<NodeId>, peer: NodeId) -> Option<String> {
+    if !connected_peers.insert(peer) {
+        return None;
+    }
+    Some(format!("{} has joined ({}/{} people in room)", short_id(&peer), connected_peers.len() + 1, ROOM_CAPACITY))
+}
+
+pub async fn send_room_info(sender: &GossipSender, my_id: NodeId, title: &str) {
+    let _ = sender.broadcast(Message::new(MessageBody::RoomInfo {
+        from: my_id,
+        title: title.to_string(),
+    }).to_vec().into()).await;
+}
+
+const ROOM_FULL_DEBOUNCE: std::time::Duration = std::time::Duration::from_secs(3);
+
+pub async fn send_room_full(
+    sender: &GossipSender,
+    my_node_id: NodeId,
+    target: NodeId,
+    last_sent: &mut HashMap<NodeId, std::time::Instant>,
+) {
+    if let Some(sent_at) = last_sent.get(&target) {
+        if sent_at.elapsed() < ROOM_FULL_DEBOUNCE {
+            return;
+        }
+    }
+    last_sent.insert(target, std::time::Instant::now());
+    let _ = sender.broadcast(Message::new(MessageBody::RoomFull {
+        from: my_node_id,
+        target,
+    }).to_vec().into()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    fn node(seed: u8) -> NodeId {
+        SecretKey::from_bytes(&[seed; 32]).public()
+    }
+
+    #[test]
+    fn garbage_messages_are_errors_and_later_ones_still_decode() {
+        let valid = Message::new(MessageBody::KeepAlive { from: node(1) }).to_vec();
+        let mut wrong_version = valid.clone();
+        wrong_version[0] = PROTOCOL_VERSION + 1;
+        let inputs: [&[u8]; 5] = [
+            &[],
+            &[PROTOCOL_VERSION, 0xff, 0xff, 0xff],
+            &valid[..valid.len() - 1],
+            &wrong_version,
+            &valid,
+        ];
+
+        // What subscribe_loop does: log and skip anything that doesn't decode
+        let decoded: Vec<Message> = inputs.iter().filter_map(|bytes| Message::from_bytes(bytes).ok()).collect();
+        assert_eq!(decoded.len(), 1);
+        assert!(matches!(decoded[0].body, MessageBody::KeepAlive { from } if from == node(1)));
+    }
+
+    #[test]
+    fn a_burst_from_one_peer_announces_a_single_join() {
+        let mut connected_peers = std::collections::HashSet::new();
+        let lines: Vec<String> = (0..50).filter_map(|_| join_line(&mut connected_peers, node(1))).collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(&short_id(&node(1))));
+        assert!(lines[0].contains(&format!("(2/{} people in room)", ROOM_CAPACITY)));
+
+        // A second peer still gets its own line, and the first rejoins only after leaving
+        assert!(join_line(&mut connected_peers, node(2)).is_some());
+        assert!(join_line(&mut connected_peers, node(1)).is_none());
+        connected_peers.remove(&node(1));
+        assert!(join_line(&mut connected_peers, node(1)).is_some());
+    }
+}
@@ -0,0 +1,59 @@
+use anyhow::{anyhow, Context, Result};
+use p2p_video_chat::codec::Frame;
+use std::time::{Duration, Instant};
+use xcap::Monitor;
+
+use crate::camera::{FrameSource, SourceInfo};
+
+// Grabs the primary monitor. Screenshots return as fast as they can, so grabs are paced
+// to the capture interval here instead of by the device like a camera
+pub struct ScreenCapture {
+    monitor: Monitor,
+    interval: Duration,
+    last_grab: Option<Instant>,
+}
+
+impl ScreenCapture {
+    pub fn new(interval: Duration) -> Result<Self> {
+        let mut monitors = Monitor::all().context("failed to list monitors")?;
+        if monitors.is_empty() {
+            return Err(anyhow!("no monitor found to capture"));
+        }
+        let primary = monitors.iter().position(|monitor| monitor.is_primary()).unwrap_or(0);
+        Ok(Self {
+            monitor: monitors.swap_remove(primary),
+            interval,
+            last_grab: None,
+        })
+    }
+}
+
+impl FrameSource for ScreenCapture {
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            width: self.monitor.width(),
+            height: self.monitor.height(),
+            fps: 0,
+        }
+    }
+
+    fn grab(&mut self) -> Result<Frame> {
+        if let Some(wait) = self.last_grab.and_then(|last| self.interval.checked_sub(last.elapsed())) {
+            std::thread::sleep(wait);
+        }
+        self.last_grab = Some(Instant::now());
+        let image = self.monitor.capture_image().context("screen capture failed")?;
+        let (width, height) = (image.width(), image.height());
+        // RGBA to the RGB layout the rest of the pipeline expects
+        let data = image
+            .as_raw()
+            .chunks_exact(4)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect();
+        Ok(Frame { data, width, height })
+    }
+
+    fn is_healthy(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::Instant;
+
+use p2p_video_chat::events::AppEvent;
+
+// Running totals for the video loop; `fps` is the rate shown since the previous `tick`
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub frames_sent: u64,
+    pub frames_unchanged: u64,
+    pub frames_skipped: u64,
+    pub frames_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub fps: f32,
+    window_start: Instant,
+    window_frames: u64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            frames_sent: 0,
+            frames_unchanged: 0,
+            frames_skipped: 0,
+            frames_received: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            fps: 0.0,
+            window_start: Instant::now(),
+            window_frames: 0,
+        }
+    }
+
+    pub fn record_sent(&mut self, bytes: usize) {
+        self.frames_sent += 1;
+        self.bytes_sent += bytes as u64;
+    }
+
+    // Identical enough to the previous frame that `frames_differ` kept it off the wire
+    pub fn record_unchanged(&mut self) {
+        self.frames_unchanged += 1;
+    }
+
+    // Dropped because the previous broadcast was still in flight
+    pub fn record_skipped(&mut self) {
+        self.frames_skipped += 1;
+    }
+
+    pub fn record_received(&mut self, bytes: usize) {
+        self.frames_received += 1;
+        self.bytes_received += bytes as u64;
+        self.window_frames += 1;
+    }
+
+    pub fn tick(&mut self) {
+        let elapsed = self.window_start.elapsed().as_secs_f32();
+        if elapsed > 0.0 {
+            self.fps = self.window_frames as f32 / elapsed;
+        }
+        self.window_start = Instant::now();
+        self.window_frames = 0;
+    }
+
+    pub fn to_event(&self) -> AppEvent {
+        AppEvent::FrameStats {
+            frames_sent: self.frames_sent,
+            frames_received: self.frames_received,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            frames_skipped: self.frames_skipped,
+            frames_unchanged: self.frames_unchanged,
+            fps: self.fps,
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "sent {} frames ({} skipped while sending, {} unchanged) | received {} frames at {:.1} fps",
+            self.frames_sent, self.frames_skipped, self.frames_unchanged, self.frames_received, self.fps
+        )
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn record_calls_count_frames_and_bytes() {
+        let mut stats = Stats::new();
+        stats.record_sent(100);
+        stats.record_sent(50);
+        stats.record_unchanged();
+        stats.record_skipped();
+        stats.record_skipped();
+        stats.record_received(30);
+        assert_eq!(stats.frames_sent, 2);
+        assert_eq!(stats.bytes_sent, 150);
+        assert_eq!(stats.frames_unchanged, 1);
+        assert_eq!(stats.frames_skipped, 2);
+        assert_eq!(stats.frames_received, 1);
+        assert_eq!(stats.bytes_received, 30);
+    }
+
+    #[test]
+    fn fps_counts_received_frames_since_the_last_tick() {
+        let mut stats = Stats::new();
+        stats.window_start = Instant::now() - Duration::from_secs(2);
+        for _ in 0..10 {
+            stats.record_received(1);
+        }
+        stats.record_sent(1);
+        stats.tick();
+        assert!((4.8..=5.0).contains(&stats.fps), "fps was {}", stats.fps);
+
+        // The next window starts empty, totals carry on
+        stats.window_start = Instant::now() - Duration::from_secs(1);
+        stats.tick();
+        assert_eq!(stats.fps, 0.0);
+        assert_eq!(stats.frames_received, 10);
+    }
+
+    #[test]
+    fn snapshot_and_summary_report_the_totals() {
+        let mut stats = Stats::new();
+        stats.record_sent(10);
+        stats.record_skipped();
+        stats.record_unchanged();
+        stats.record_received(20);
+        stats.record_received(20);
+        stats.fps = 12.34;
+
+        match stats.to_event() {
+            AppEvent::FrameStats { frames_sent, frames_received, bytes_sent, bytes_received, frames_skipped, frames_unchanged, fps } => {
+                assert_eq!((frames_sent, frames_received), (1, 2));
+                assert_eq!((bytes_sent, bytes_received), (10, 40));
+                assert_eq!((frames_skipped, frames_unchanged), (1, 1));
+                assert_eq!(fps, 12.34);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+        assert_eq!(
+            stats.summary(),
+            "sent 1 frames (1 skipped while sending, 1 unchanged) | received 2 frames at 12.3 fps"
+        );
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::{Context, Result};
+use p2p_video_chat::codec::Frame;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::camera::{FrameSource, SourceInfo};
+
+// A picture from disk standing in for the camera. The send loop skips unchanged frames,
+// so after the first one only the periodic resends go out
+pub struct StillImage {
+    frame: Frame,
+    interval: Duration,
+    last_grab: Option<Instant>,
+}
+
+impl StillImage {
+    pub fn open(path: &Path, interval: Duration) -> Result<Self> {
+        let image = image::open(path)
+            .with_context(|| format!("failed to load image {}", path.display()))?
+            .into_rgb8();
+        let (width, height) = image.dimensions();
+        let frame = Frame::new(image.into_raw(), width, height)
+            .with_context(|| format!("image {} is empty", path.display()))?;
+        Ok(Self {
+            frame,
+            interval,
+            last_grab: None,
+        })
+    }
+}
+
+impl FrameSource for StillImage {
+    fn info(&self) -> SourceInfo {
+        SourceInfo {
+            width: self.frame.width,
+            height: self.frame.height,
+            fps: 0,
+        }
+    }
+
+    // Paced like a screen grab so the worker thread doesn't spin handing out copies
+    fn grab(&mut self) -> Result<Frame> {
+        if let Some(wait) = self.last_grab.and_then(|last| self.interval.checked_sub(last.elapsed())) {
+            std::thread::sleep(wait);
+        }
+        self.last_grab = Some(Instant::now());
+        Ok(self.frame.clone())
+    }
+
+    fn is_healthy(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use colored::control;
+use p2p_video_chat::display::{self, DisplayOptions, TerminalDisplay};
+
+const TEST_W: u32 = 640;
+const TEST_H: u32 = 480;
+const FRAMES_PER_TRY: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Truecolor,
+    Color256,
+    Ascii,
+}
+
+impl Backend {
+    fn name(self) -> &'static str {
+        match self {
+            Backend::Truecolor => "truecolor",
+            Backend::Color256 => "256 colors",
+            Backend::Ascii => "ascii",
+        }
+    }
+}
+
+// Busy enough that nearly every cell needs its own escapes, the worst a real picture gets
+fn test_frame(offset: u32) -> Vec<u8> {
+    let mut frame = Vec::with_capacity((TEST_W * TEST_H * 3) as usize);
+    for y in 0..TEST_H {
+        for x in 0..TEST_W {
+            frame.push(((x * 7 + offset * 13) % 256) as u8);
+            frame.push(((y * 5 + x * 3 + offset * 29) % 256) as u8);
+            frame.push(((x ^ y).wrapping_add(offset * 17) % 256) as u8);
+        }
+    }
+    frame
+}
+
+// Average time to compose, write and flush one test frame with these options
+fn time_frames(options: &DisplayOptions, frames: &[Vec<u8>]) -> Result<Duration> {
+    let mut display = TerminalDisplay::new(TEST_W, TEST_H, options);
+    let started = Instant::now();
+    for i in 0..FRAMES_PER_TRY {
+        display.show_frame(&frames[i as usize % frames.len()])?;
+    }
+    Ok(started.elapsed() / FRAMES_PER_TRY)
+}
+
+// Tries the richest backend at the largest size first and settles on the first one the
+// terminal can draw within `frame_budget`, or the cheapest when none of them can
+pub fn auto_tune(options: &DisplayOptions, frame_budget: Duration) -> Result<DisplayOptions> {
+    let (cols, rows) = options.fixed_size.unwrap_or_else(|| {
+        let (cols, rows) = display::term_size();
+        // Two rows stay free for the caption and status lines
+        (cols, rows.saturating_sub(2))
+    });
+    let mut backends = Vec::new();
+    if !options.ascii && control::SHOULD_COLORIZE.should_colorize() {
+        if display::supports_truecolor() {
+            backends.push(Backend::Truecolor);
+        }
+        backends.push(Backend::Color256);
+    }
+    backends.push(Backend::Ascii);
+    let sizes = [(cols, rows), (cols * 3 / 4, rows * 3 / 4), (cols / 2, rows / 2)];
+    let frames: Vec<Vec<u8>> = (0..3).map(test_frame).collect();
+
+    let mut chosen = None;
+    'search: for backend in backends {
+        for (i, &size) in sizes.iter().enumerate() {
+            let candidate = DisplayOptions {
+                ascii: backend == Backend::Ascii,
+                truecolor: backend == Backend::Truecolor,
+                // The full terminal keeps following resizes, only a shrunk area is pinned
+                fixed_size: if i == 0 { options.fixed_size } else { Some(size) },
+                ..options.clone()
+            };
+            let per_frame = time_frames(&candidate, &frames)?;
+            chosen = Some((candidate, backend, size, per_frame));
+            if per_frame <= frame_budget {
+                break 'search;
+            }
+        }
+    }
+    let Some((chosen, backend, (cols, rows), per_frame)) = chosen else {
+        return Ok(options.clone());
+    };
+
+    if control::SHOULD_COLORIZE.should_colorize() {
+        print!("\x1B[2J\x1B[H");
+    }
+    println!(
+        "> auto-tune: {} at {}x{} cells, {:.1} ms per frame (budget {} ms)",
+        backend.name(),
+        cols,
+        rows,
+        per_frame.as_secs_f64() * 1000.0,
+        frame_budget.as_millis()
+    );
+    if per_frame > frame_budget {
+        println!("> auto-tune: this terminal can't keep up with the frame rate even at the lowest setting");
+    }
+    Ok(chosen)
+}
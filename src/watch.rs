@@ -0,0 +1,170 @@
+use anyhow::{Context, Result};
+use futures_lite::StreamExt;
+use iroh::{Endpoint, NodeAddr, NodeId};
+use iroh_gossip::api::{Event, GossipReceiver, GossipSender};
+use iroh_gossip::net::Gossip;
+use tokio::sync::mpsc;
+
+use p2p_video_chat::codec::{Capabilities, Frame};
+use p2p_video_chat::display::{self, DisplayOptions, TerminalDisplay};
+use p2p_video_chat::frame::Mosaic;
+
+use p2p_video_chat::events::{AppEvent, EventSink};
+use p2p_video_chat::room::{short_id, Failure, Message, MessageBody};
+
+use crate::{password_proof, spawn_keepalive, AddrFamily, Spinner, Ticket};
+
+// Every room gets a cell this size in the mosaic, and asks its sender for no more than that
+const TILE_W: u32 = 320;
+const TILE_H: u32 = 240;
+
+pub struct WatchOptions {
+    pub password: Option<String>,
+    pub nick: Option<String>,
+    pub keepalive_secs: u64,
+    pub prefer: AddrFamily,
+    pub display: DisplayOptions,
+}
+
+enum TileUpdate {
+    Frame(Frame),
+    // Chat text sent to the room, shown as a caption
+    Notice(String),
+    // The room can't be watched any more, with the reason
+    Ended(String),
+}
+
+// Watches several rooms at once, receive-only, each room's video in its own tile
+pub async fn run(
+    endpoint: &Endpoint,
+    gossip: &Gossip,
+    tickets: Vec<Ticket>,
+    events: EventSink,
+    options: WatchOptions,
+) -> Result<()> {
+    let (tile_tx, mut tile_rx) = mpsc::unbounded_channel::<(usize, TileUpdate)>();
+    let my_id = endpoint.node_id();
+
+    for (index, ticket) in tickets.iter().enumerate() {
+        let bootstrap: Vec<NodeAddr> = ticket
+            .nodes
+            .iter()
+            .map(|node| node.to_node_addr(options.prefer))
+            .collect();
+        for addr in &bootstrap {
+            endpoint.add_node_addr(addr.clone())?;
+        }
+        match &ticket.host_name {
+            Some(host_name) => println!("> joining room {} ({}'s room)...", index + 1, host_name),
+            None => println!("> joining room {}...", index + 1),
+        }
+        events.emit(AppEvent::Connecting {
+            topic: ticket.topic.to_string(),
+        });
+        let spinner = Spinner::start();
+        let (sender, receiver) = gossip
+            .subscribe_and_join(ticket.topic, bootstrap.iter().map(|addr| addr.node_id).collect())
+            .await
+            .with_context(|| format!("room {}", index + 1))
+            .context(Failure::ConnectFailed)?
+            .split();
+        drop(spinner);
+
+        sender.broadcast(Message::new(MessageBody::AboutMe {
+            from: my_id,
+            proof: options.password.as_deref().map(|password| password_proof(password, &ticket.topic)),
+            view: Some((TILE_W, TILE_H)),
+            caps: Capabilities::video(TILE_W, TILE_H),
+            nick: options.nick.clone(),
+        }).to_vec().into()).await?;
+        spawn_keepalive(sender.clone(), my_id, options.keepalive_secs);
+        tokio::spawn(watch_loop(index, receiver, sender, my_id, tile_tx.clone()));
+    }
+    println!("> watching {} rooms, Ctrl+C to quit", tickets.len());
+    events.emit(AppEvent::Connected);
+
+    let mut mosaic = Mosaic::new(tickets.len(), TILE_W, TILE_H);
+    let (width, height) = mosaic.dimensions();
+    let mut display = TerminalDisplay::new(width, height, &options.display);
+    display.set_status(Some(format!("watching {} rooms", tickets.len())));
+    let mut resize_rx = display::watch_resize();
+    // Tiles update on their own schedules, so the screen is redrawn at a steady rate instead
+    let mut render_interval = tokio::time::interval(std::time::Duration::from_millis(33));
+    let mut dirty = false;
+
+    loop {
+        tokio::select! {
+            Some((index, update)) = tile_rx.recv() => {
+                match update {
+                    TileUpdate::Frame(frame) => mosaic.set_tile(index, &frame.data, frame.width, frame.height),
+                    TileUpdate::Notice(text) => display.set_caption(format!("> room {}: {}", index + 1, text)),
+                    TileUpdate::Ended(reason) => {
+                        mosaic.clear_tile(index);
+                        display.set_caption(format!("> room {}: {}", index + 1, reason));
+                    }
+                }
+                dirty = true;
+            }
+            _ = render_interval.tick(), if dirty => {
+                dirty = false;
+                if let Err(e) = display.show_frame(mosaic.data()) {
+                    eprintln!("Display error: {}", e);
+                }
+            }
+            Some(()) = resize_rx.recv() => {
+                if let Err(e) = display.handle_resize() {
+                    eprintln!("Display error: {}", e);
+                }
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    Ok(())
+}
+
+// A cut-down receive loop: no admission or chat, just this room's video and the reasons it can stop
+async fn watch_loop(
+    index: usize,
+    mut receiver: GossipReceiver,
+    sender: GossipSender,
+    my_id: NodeId,
+    tile_tx: mpsc::UnboundedSender<(usize, TileUpdate)>,
+) -> Result<()> {
+    let mut shown_peer: Option<NodeId> = None;
+    while let Some(event) = receiver.try_next().await? {
+        let Event::Received(msg) = event else {
+            continue;
+        };
+        let Ok(message) = Message::from_bytes(&msg.content) else {
+            continue;
+        };
+        let ended = match message.body {
+            MessageBody::VideoFrame { from, frame, checksum } => {
+                let intact = frame.is_valid() && checksum.is_none_or(|sum| sum == frame.checksum());
+                // One feed per tile, whoever sent first
+                if from != my_id && intact && *shown_peer.get_or_insert(from) == from {
+                    let _ = tile_tx.send((index, TileUpdate::Frame(frame)));
+                }
+                None
+            }
+            MessageBody::RoomFull { target, .. } if target == my_id => Some("room is full".to_string()),
+            MessageBody::AuthFailed { target, .. } if target == my_id => Some("wrong password".to_string()),
+            MessageBody::Kicked { target, .. } if target == my_id => Some("the host removed you".to_string()),
+            MessageBody::RoomClosed { from } if from != my_id => Some("host closed the room".to_string()),
+            MessageBody::Message { from, text } => {
+                let _ = sender.broadcast(Message::new(MessageBody::Ack {
+                    from: my_id,
+                    nonce: message.nonce,
+                }).to_vec().into()).await;
+                let _ = tile_tx.send((index, TileUpdate::Notice(format!("{}: {}", short_id(&from), text))));
+                None
+            }
+            _ => None,
+        };
+        if let Some(reason) = ended {
+            let _ = tile_tx.send((index, TileUpdate::Ended(reason)));
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use iroh::{protocol::Router, Endpoint, RelayMode, Watcher};
+use iroh_gossip::{
+    net::{Gossip, GOSSIP_ALPN},
+    proto::TopicId,
+};
+use tokio::sync::mpsc;
+
+use p2p_video_chat::codec::Frame;
+use p2p_video_chat::events::EventSink;
+use p2p_video_chat::room::{
+    short_id, subscribe_loop, Activity, Message, MessageBody, Rejoin, RoomSettings, VideoChannels, MAX_MESSAGE_SIZE,
+};
+
+// Generous, the first connection between two fresh endpoints can take a moment
+const WAIT: Duration = Duration::from_secs(20);
+
+// A node on this machine only: no relays or discovery, peers reach it through its direct addresses
+async fn node() -> (Endpoint, Gossip, Router) {
+    let endpoint = Endpoint::builder().relay_mode(RelayMode::Disabled).bind().await.unwrap();
+    let gossip = Gossip::builder().max_message_size(MAX_MESSAGE_SIZE).spawn(endpoint.clone());
+    let router = Router::builder(endpoint.clone()).accept(GOSSIP_ALPN, gossip.clone()).spawn();
+    (endpoint, gossip, router)
+}
+
+#[tokio::test]
+async fn frames_and_messages_reach_the_host_through_subscribe_loop() {
+    let (host, host_gossip, _host_router) = node().await;
+    let (guest, guest_gossip, _guest_router) = node().await;
+    let topic = TopicId::from_bytes(rand::random());
+
+    // The address a ticket carries, which is all `join` knows about the host
+    let host_addr = tokio::time::timeout(WAIT, host.node_addr().initialized()).await.unwrap();
+    guest.add_node_addr(host_addr).unwrap();
+
+    // The host waits for its first neighbor, so both sides join at once
+    let (host_topic, guest_topic) = tokio::time::timeout(WAIT, async {
+        tokio::join!(
+            host_gossip.subscribe_and_join(topic, Vec::new()),
+            guest_gossip.subscribe_and_join(topic, vec![host.node_id()]),
+        )
+    })
+    .await
+    .unwrap();
+    let (host_sender, host_receiver) = host_topic.unwrap().split();
+    let (guest_sender, _guest_receiver) = guest_topic.unwrap().split();
+
+    let (frame_tx, mut frame_rx) = mpsc::unbounded_channel();
+    let (caption_tx, mut caption_rx) = mpsc::unbounded_channel();
+    let (_kick_tx, kick_rx) = mpsc::unbounded_channel();
+    let (view_tx, _view_rx) = mpsc::unbounded_channel();
+    let (caps_tx, _caps_rx) = mpsc::unbounded_channel();
+    let (presence_tx, _presence_rx) = mpsc::unbounded_channel();
+    let (full_frame_tx, _full_frame_rx) = mpsc::unbounded_channel();
+    let (occupancy_tx, _occupancy_rx) = mpsc::unbounded_channel();
+    tokio::spawn(subscribe_loop(
+        host_receiver,
+        host_sender,
+        host.node_id(),
+        RoomSettings {
+            required_proof: None,
+            title: None,
+            rejoin: Rejoin { endpoint: host.clone(), peers: Vec::new() },
+            peer_timeout: None,
+        },
+        Activity::default(),
+        VideoChannels {
+            frames: frame_tx,
+            captions: caption_tx,
+            kicks: kick_rx,
+            views: view_tx,
+            caps: caps_tx,
+            presence: presence_tx,
+            full_frame_requests: full_frame_tx,
+            occupancy: occupancy_tx,
+        },
+        EventSink::disabled(),
+    ));
+
+    let frame = Frame::placeholder();
+    let message = Message::new(MessageBody::VideoFrame {
+        from: guest.node_id(),
+        checksum: Some(frame.checksum()),
+        frame: frame.clone(),
+    });
+    guest_sender.broadcast(message.to_vec().into()).await.unwrap();
+    let (from, received) = tokio::time::timeout(WAIT, frame_rx.recv()).await.unwrap().unwrap();
+    assert_eq!(from, guest.node_id());
+    assert_eq!(received, frame);
+
+    let message = Message::new(MessageBody::Message {
+        from: guest.node_id(),
+        text: "hello from the guest".to_string(),
+    });
+    guest_sender.broadcast(message.to_vec().into()).await.unwrap();
+    let expected = format!("{}: hello from the guest", short_id(&guest.node_id()));
+    let caption = tokio::time::timeout(WAIT, async {
+        loop {
+            // Connection notices from the room can come first
+            let caption = caption_rx.recv().await.unwrap();
+            if caption == expected {
+                return caption;
+            }
+        }
+    })
+    .await;
+    assert!(caption.is_ok(), "never saw {:?}", expected);
+}